@@ -3,7 +3,7 @@ use futures::executor::block_on;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use log::{debug, info, trace, warn};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
-use std::{fs, io::BufRead, ops::Mul, path::PathBuf, sync::Arc, time::Duration};
+use std::{ops::Mul, path::PathBuf, sync::Arc, time::Duration};
 use tokio::sync::Semaphore;
 use tokio::task::JoinSet;
 
@@ -334,7 +334,7 @@ pub async fn handle_hovers(
 }
 
 pub fn main(
-    file_list: &str,
+    files_list: Vec<String>,
     debug: bool,
     log_file: String,
     compile_commands_dir: String,
@@ -355,14 +355,7 @@ pub fn main(
         .build()
         .unwrap();
 
-    let file_content = fs::read(file_list)?;
-    let files_list: Vec<String> = std::io::BufReader::new(&file_content[..])
-        .lines()
-        .filter_map(Result::ok)
-        .filter(|file| !file.is_empty())
-        .map(|line| line.trim().to_string())
-        .collect();
-
+    info!("Total files to index: {}", files_list.len());
     info!("build file index");
     let mut file_index_builder = lsp::builder::FileIndexBuilder::from(());
     files_list.into_iter().try_for_each(|file_name| {
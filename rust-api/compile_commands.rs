@@ -0,0 +1,123 @@
+use crate::walk::FileWalker;
+use anyhow::{Result, anyhow};
+use log::warn;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+const COMPILE_COMMANDS: &str = "compile_commands.json";
+/// Extensions of the headers picked up below the include roots.
+const HEADER_EXTENSIONS: &[&str] = &["h", "hh", "hpp", "hxx", "h++", "inc", "inl", "ipp", "tcc"];
+
+/// One entry of `compile_commands.json`, the compiler flags are not needed.
+#[derive(Debug, Deserialize)]
+struct CompileCommand {
+    directory: String,
+    file: String,
+}
+
+/// The files of a compilation database, shared by the ngram and the LSP index
+/// so both cover the same files.
+#[derive(Debug, Default)]
+pub struct CompileCommands {
+    commands: Vec<CompileCommand>,
+    include_roots: Vec<PathBuf>,
+}
+
+impl CompileCommands {
+    /// Reads `compile_commands.json` of `compile_commands_dir`.
+    pub fn load(compile_commands_dir: &Path) -> Result<Self> {
+        let path = compile_commands_dir.join(COMPILE_COMMANDS);
+        let data = std::fs::read(&path).map_err(|e| anyhow!("read {:?} fail. {:?}", path, e))?;
+        let commands = serde_json::from_slice(&data)
+            .map_err(|e| anyhow!("parse {:?} fail. {:?}", path, e))?;
+        Ok(CompileCommands {
+            commands,
+            include_roots: vec![],
+        })
+    }
+
+    /// Headers below these directories are added to the translation units,
+    /// honoring ignore files like `--root`.
+    pub fn with_include_roots(mut self, include_roots: Vec<PathBuf>) -> Self {
+        self.include_roots = include_roots;
+        self
+    }
+
+    /// Absolute file names of the translation units and headers, sorted.
+    pub fn files(&self) -> Result<Vec<String>> {
+        let mut files = self.translation_units();
+        if !self.include_roots.is_empty() {
+            let headers = FileWalker::from(self.include_roots.clone()).files()?;
+            files.extend(headers.into_iter().filter(|file| CompileCommands::is_header(file)));
+        }
+        files.sort();
+        files.dedup();
+        Ok(files)
+    }
+
+    /// `file` is relative to `directory` unless absolute, missing files are skipped.
+    fn translation_units(&self) -> Vec<String> {
+        self.commands
+            .iter()
+            .filter_map(|command| {
+                let path = Path::new(&command.directory).join(&command.file);
+                path.canonicalize()
+                    .map_err(|e| warn!("translation unit {:?} skipped. {:?}", path, e))
+                    .ok()
+                    .and_then(|path| path.to_str().map(str::to_string))
+            })
+            .collect()
+    }
+
+    fn is_header(file: &str) -> bool {
+        Path::new(file)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| HEADER_EXTENSIONS.contains(&extension))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TempDir;
+
+    #[test]
+    fn compile_commands_list_units_and_headers() {
+        let temp = TempDir::from("ccdb");
+        let dir = temp.path();
+        let write = |name: &str, content: &str| {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        };
+        write("src/a.cc", "");
+        write("src/b.cc", "");
+        write("include/a.h", "");
+        write("include/detail/b.hpp", "");
+        write("include/README", "");
+        let root = dir.canonicalize().unwrap();
+        let commands = serde_json::json!([
+            {"directory": root.join("src"), "file": "a.cc", "command": "c++ -c a.cc"},
+            {"directory": root, "file": root.join("src/b.cc"), "arguments": ["c++", "-c"]},
+            {"directory": root, "file": "src/a.cc", "command": "c++ -O2 -c src/a.cc"},
+            {"directory": root, "file": "src/gone.cc", "command": "c++ -c src/gone.cc"},
+        ]);
+        write("build/compile_commands.json", &commands.to_string());
+
+        let relative = |files: Vec<String>| {
+            files
+                .iter()
+                .map(|file| file.strip_prefix(root.to_str().unwrap()).unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        let commands = CompileCommands::load(&dir.join("build")).unwrap();
+        assert_eq!(relative(commands.files().unwrap()), vec!["/src/a.cc", "/src/b.cc"]);
+        let commands = commands.with_include_roots(vec![dir.join("include")]);
+        assert_eq!(
+            relative(commands.files().unwrap()),
+            vec!["/include/a.h", "/include/detail/b.hpp", "/src/a.cc", "/src/b.cc"]
+        );
+        assert!(CompileCommands::load(dir).is_err());
+    }
+}
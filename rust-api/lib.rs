@@ -8,6 +8,8 @@ pub mod search;
 pub mod lsp;
 pub mod ngram;
 pub mod web_api;
+#[cfg(test)]
+mod test_dir;
 
 use wasm_bindgen::prelude::*;
 
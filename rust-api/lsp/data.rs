@@ -6,6 +6,7 @@ use std::{
 };
 
 use crate::lsp::index::FileIndex;
+use crate::ngram::encoding::{TextEncoding, split_lines};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum TreeData {
@@ -301,17 +302,20 @@ impl TryFrom<(FileContentData, Option<FileSemanticTokensData>)> for FileData {
 impl TryFrom<&FileIndex> for FileContentData {
     type Error = anyhow::Error;
 
+    /// Lines decoded from the detected encoding of the file, so files that
+    /// are not UTF-8 keep their line numbers.
     fn try_from(file_index: &FileIndex) -> Result<Self> {
-        let lines = fs::read_to_string(file_index.path())
-            .map_err(|e| {
-                anyhow!(
-                    "Failed to read file at path: {:?}, error: {:?}",
-                    file_index.path(),
-                    e
-                )
-            })?
-            .lines()
-            .map(|line| line.to_string())
+        let content = fs::read(file_index.path()).map_err(|e| {
+            anyhow!(
+                "Failed to read file at path: {:?}, error: {:?}",
+                file_index.path(),
+                e
+            )
+        })?;
+        let encoding = TextEncoding::detect(&content);
+        let lines = split_lines(&content)
+            .iter()
+            .map(|line| encoding.decode(line).into_owned())
             .collect();
         Ok(Self { lines })
     }
@@ -1,5 +1,6 @@
 mod builder;
 mod clang;
+mod compile_commands;
 mod config;
 mod data;
 mod encode;
@@ -8,19 +9,32 @@ mod lsp;
 mod ngram;
 mod range;
 mod search;
+#[cfg(test)]
+mod test_dir;
+mod walk;
 
 use crate::ngram::builder::FileIndexFinalBuilder;
 use crate::ngram::search::NgramIndexData;
-use crate::ngram::search::{SearchEngine, SearchOneNgramResult};
+use crate::ngram::search::{
+    PathFilter, SearchEngine, SearchOneEngine, SearchOneNgramResult, SearchOptions,
+};
 use crate::search::{Engine, FileDataMatchRange, NgreamIndexData};
 use crate::{
     builder::{AbsPath, Builder, FileContent, FileIndexBuilder},
     range::Offset,
 };
 
-use crate::ngram::data::{FileData, FromToData, GlobalData, NgramData};
-use crate::ngram::index::NgramIndex;
-use crate::ngram::path::{FilePath, GetPath, GlobalDataPath, NgramPath};
+use crate::ngram::data::{FileData, FromToData, GlobalData, NgramData, PathTable};
+use crate::ngram::filter::{DEFAULT_MAX_FILE_SIZE, DEFAULT_MAX_LINE_LEN, FileFilter};
+use crate::ngram::json::JsonPrinter;
+use crate::ngram::rank::DefinitionLines;
+use crate::ngram::index::{FileIndex, NgramIndexVec};
+use crate::ngram::search::{SearchOneFileLinesContentResult, SearchOneLineContentResult};
+use crate::compile_commands::CompileCommands;
+use crate::walk::FileWalker;
+use crate::ngram::path::{
+    FilePath, GetPath, GlobalDataPath, NgramPath, NgramShardPath, PathTablePath,
+};
 use log::{debug, error, info, warn};
 
 use anyhow::{Result, anyhow};
@@ -29,6 +43,7 @@ use colored::Colorize;
 use rayon::prelude::*;
 use regex_syntax::ast::print;
 
+use std::collections::HashSet;
 use std::fs;
 use std::ops::Not;
 use std::path::PathBuf;
@@ -39,6 +54,9 @@ use std::{
     time::Instant,
 };
 
+/// File blobs read and matched in parallel at a time by search-new.
+const SEARCH_BATCH: usize = 1024;
+
 /// Indexed grep tool
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -57,18 +75,41 @@ enum Commands {
     Index(IndexArgs),
     /// Index files for faster searching
     IndexNew(IndexArgs),
+    /// Update an index built by index-new, only re-indexing changed files
+    IndexUpdate(IndexUpdateArgs),
     /// Search through indexed files
     Search(SearchArgs),
     /// Search through indexed files
     SearchNew(SearchArgs),
+    /// Show the ngram plan of a search-new search, no file blob is read
+    Explain(SearchArgs),
     ClangIndex(ClangIndexArgs),
 }
 
 #[derive(Parser)]
 struct IndexArgs {
     /// Sets the file to be indexed
-    #[arg(short, long, required = true)]
-    file_list: String,
+    #[arg(short, long, required_unless_present_any = ["root", "compile_commands_dir"])]
+    file_list: Option<String>,
+
+    /// Index every file below this directory, honoring .gitignore, .ignore and
+    /// .igrepignore files (repeatable, adds to --file-list)
+    #[arg(long)]
+    root: Vec<PathBuf>,
+
+    /// Follow symbolic links while walking --root directories
+    #[arg(long, default_value_t = false)]
+    follow_symlinks: bool,
+
+    /// Index the translation units of compile_commands.json in this directory,
+    /// the same files clang-index covers (adds to --file-list)
+    #[arg(long)]
+    compile_commands_dir: Option<PathBuf>,
+
+    /// Also index the headers below this directory (repeatable, needs
+    /// --compile-commands-dir)
+    #[arg(long, requires = "compile_commands_dir")]
+    include_root: Vec<PathBuf>,
 
     /// Sets the config file path
     #[arg(short, long, default_value = "ngram-index")]
@@ -77,6 +118,50 @@ struct IndexArgs {
     /// Sets the size of n-gram
     #[arg(short, long, default_value_t = 3)]
     ngram: u8,
+
+    /// Pack ngram posting lists into this many shard files (index-new only)
+    #[arg(long)]
+    pack_shards: Option<u32>,
+
+    /// Also index lowercased lines, so case insensitive searches can use ngrams
+    /// (index-new only)
+    #[arg(long, default_value_t = false)]
+    case_fold: bool,
+
+    /// Index sparse ngrams of 3 up to --ngram bytes instead of fixed ones (index-new only)
+    #[arg(long, default_value_t = false)]
+    sparse: bool,
+
+    /// Build with about this many MiB, posting entries are spilled to sorted
+    /// runs on disk and merged at the end (index-new only)
+    #[arg(long)]
+    memory_limit: Option<usize>,
+
+    /// Skip files larger than this many KiB, 2 MiB by default. Skipped files
+    /// are counted by reason after indexing (index-new only)
+    #[arg(long, default_value_t = DEFAULT_MAX_FILE_SIZE >> 10)]
+    max_file_size: u64,
+
+    /// Skip files with a line longer than this many bytes, minified sources
+    /// mostly (index-new only)
+    #[arg(long, default_value_t = DEFAULT_MAX_LINE_LEN)]
+    max_line_length: usize,
+
+    /// Also index files marked @generated or DO NOT EDIT in their first 20
+    /// lines, skipped by default (index-new only)
+    #[arg(long, default_value_t = false)]
+    index_generated: bool,
+}
+
+#[derive(Parser)]
+struct IndexUpdateArgs {
+    /// Sets the new list of files to be indexed
+    #[arg(short, long, required = true)]
+    file_list: String,
+
+    /// Sets the config file path
+    #[arg(short, long, default_value = "ngram-index")]
+    config: String,
 }
 
 #[derive(Parser)]
@@ -88,6 +173,86 @@ struct SearchArgs {
     /// The search term to look for
     #[arg(required = true)]
     search_term: String,
+
+    /// Refuse patterns without usable ngrams when the full scan reads more files than this
+    #[arg(long)]
+    scan_limit: Option<usize>,
+
+    /// Match case insensitively, same as a leading `(?i)`
+    #[arg(short, long, default_value_t = false)]
+    ignore_case: bool,
+
+    /// Treat the search term as a literal string instead of a regex
+    #[arg(short = 'F', long, default_value_t = false)]
+    fixed_strings: bool,
+
+    /// Parse `path:`, `glob:` and `lang:` filters and `AND`, `OR` and `NOT` out of the search term
+    #[arg(short = 'Q', long, default_value_t = false, conflicts_with = "fixed_strings")]
+    query: bool,
+
+    /// Let matches span lines, `\n` in the pattern matches a line end
+    #[arg(short = 'U', long, default_value_t = false)]
+    multiline: bool,
+
+    /// Show this many lines after each match
+    #[arg(short = 'A', long)]
+    after_context: Option<u32>,
+
+    /// Show this many lines before each match
+    #[arg(short = 'B', long)]
+    before_context: Option<u32>,
+
+    /// Show this many lines before and after each match
+    #[arg(short = 'C', long)]
+    context: Option<u32>,
+
+    /// Only search files matching this glob, `!` in front excludes them instead.
+    /// Same as `glob:`/`-glob:` in the search term of `--query`
+    #[arg(short = 'g', long)]
+    glob: Vec<String>,
+
+    /// Only print the names of the files with a match
+    #[arg(short = 'l', long, default_value_t = false, conflicts_with = "count")]
+    files_with_matches: bool,
+
+    /// Only print the number of matched lines of every file with a match
+    #[arg(long, default_value_t = false)]
+    count: bool,
+
+    /// Stop searching a file after this many matched lines
+    #[arg(short = 'm', long)]
+    max_count: Option<u32>,
+
+    /// Print results as JSON Lines, in the message format of ripgrep's `--json`
+    #[arg(long, default_value_t = false, conflicts_with_all = ["files_with_matches", "count"])]
+    json: bool,
+
+    /// Print `path:line:column:content` for every match, columns count characters
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["files_with_matches", "count", "json"]
+    )]
+    vimgrep: bool,
+
+    /// Only search page N of the files, counted from 1, ordered by relevance
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    page: Option<usize>,
+
+    /// Files on a page of `--page`
+    #[arg(long, default_value_t = 50, requires = "page")]
+    page_size: usize,
+
+    /// LSP index directory of `clang-index`, ranks files with a definition on a matched line higher
+    #[arg(long, requires = "page")]
+    lsp_index: Option<String>,
+}
+
+/// What `search-new` prints for one file.
+enum FileOutput {
+    Lines(SearchOneFileLinesContentResult),
+    Count(u32),
+    Matched(bool),
 }
 
 #[derive(Parser)]
@@ -104,8 +269,14 @@ struct ClangIndexArgs {
     #[arg(long, default_value_t = false)]
     debug: bool,
 
-    #[arg(short, long, required = true)]
-    file_list: String,
+    /// Defaults to the files of compile_commands.json
+    #[arg(short, long)]
+    file_list: Option<String>,
+
+    /// Headers below this directory are added to the files of
+    /// compile_commands.json (repeatable)
+    #[arg(long, conflicts_with = "file_list")]
+    include_root: Vec<PathBuf>,
 
     #[arg(short = 'j', long)]
     jobs: Option<usize>,
@@ -122,12 +293,20 @@ fn main() -> Result<()> {
     match cli.command {
         Commands::Index(args) => run_index(args, cli.verbose),
         Commands::IndexNew(args) => run_index_new(args, cli.verbose),
+        Commands::IndexUpdate(args) => run_index_update(args, cli.verbose),
         Commands::Search(args) => run_search(args, cli.verbose),
         Commands::SearchNew(args) => run_search_new(args, cli.verbose),
+        Commands::Explain(args) => run_explain(args),
         Commands::ClangIndex(args) => {
+            let files_list = match &args.file_list {
+                Some(file_list) => read_file_list(file_list)?,
+                None => CompileCommands::load(Path::new(&args.compile_commands_dir))?
+                    .with_include_roots(args.include_root.clone())
+                    .files()?,
+            };
             // Call the Clang indexing logic with the provided file
             clang::clangd_lsp_client::main(
-                &args.file_list,
+                files_list,
                 args.debug,
                 args.log.clone(),
                 args.compile_commands_dir.to_string(),
@@ -139,34 +318,104 @@ fn main() -> Result<()> {
     }
 }
 
-fn run_index_new(args: IndexArgs, verbose: bool) -> Result<()> {
-    // 读取文件列表
-    let file_content = fs::read(args.file_list.clone())?;
-    let files_list: Vec<String> = std::io::BufReader::new(&file_content[..])
+/// One file name per line, blank lines skipped.
+fn read_file_list(file_list: &str) -> Result<Vec<String>> {
+    let file_content = fs::read(file_list)?;
+    Ok(std::io::BufReader::new(&file_content[..])
         .lines()
-        .filter_map(Result::ok)
+        .map_while(Result::ok)
         .filter(|file| !file.is_empty())
         .map(|line| line.trim().to_string())
-        .collect();
+        .collect())
+}
+
+/// The files of `--file-list`, then of compile_commands.json, then the files
+/// found below `--root`, each file once.
+fn index_files(args: &IndexArgs) -> Result<Vec<String>> {
+    let mut files_list = match &args.file_list {
+        Some(file_list) => read_file_list(file_list)?,
+        None => vec![],
+    };
+    let mut sources = vec![];
+    if let Some(compile_commands_dir) = &args.compile_commands_dir {
+        let commands = CompileCommands::load(compile_commands_dir)?
+            .with_include_roots(args.include_root.clone());
+        sources.push(commands.files()?);
+    }
+    if !args.root.is_empty() {
+        let walker = FileWalker::from(args.root.clone()).with_follow_symlinks(args.follow_symlinks);
+        sources.push(walker.files()?);
+    }
+    let mut listed = files_list.iter().cloned().collect::<HashSet<_>>();
+    sources.into_iter().flatten().for_each(|file| {
+        if listed.insert(file.clone()) {
+            files_list.push(file);
+        }
+    });
+    Ok(files_list)
+}
+
+fn run_index_new(args: IndexArgs, verbose: bool) -> Result<()> {
+    // 读取文件列表
+    let files_list = index_files(&args)?;
     let total_files = files_list.len();
     info!("Total files to index: {}", total_files);
     let mut file_builder = crate::ngram::builder::FileIndexBuilder::new();
     file_builder.build(files_list)?;
-    let mut builder = crate::ngram::builder::Builder::new(args.ngram)?;
-    builder.index(FileIndexFinalBuilder::try_from(file_builder)?)?;
+    let filter = FileFilter::default()
+        .with_max_file_size(args.max_file_size << 10)
+        .with_max_line_len(args.max_line_length)
+        .with_skip_generated(!args.index_generated);
+    let mut builder = crate::ngram::builder::Builder::new(args.ngram)?
+        .with_pack_shards(args.pack_shards)?
+        .with_case_fold(args.case_fold)
+        .with_sparse(args.sparse)
+        .with_file_filter(filter);
     let base_path = &PathBuf::from_str(args.config.as_str())?;
-    builder.dump(base_path.as_path())?;
+    let skipped = match args.memory_limit {
+        Some(limit) => builder.index_streaming(file_builder, base_path, limit << 20)?,
+        None => {
+            builder.index(FileIndexFinalBuilder::try_from((file_builder, &filter))?)?;
+            builder.dump(base_path.as_path())?;
+            builder.skipped().clone()
+        }
+    };
+    println!("{}", skipped);
     Ok(())
 }
+fn run_index_update(args: IndexUpdateArgs, _verbose: bool) -> Result<()> {
+    let files_list = read_file_list(&args.file_list)?;
+    info!("Total files to index: {}", files_list.len());
+    let base_path = &PathBuf::from_str(args.config.as_str())?;
+    let builder = crate::ngram::builder::UpdateBuilder::load(base_path.as_path())?;
+    let skipped = builder.update(base_path.as_path(), files_list)?;
+    println!("{}", skipped);
+    Ok(())
+}
+
+/// Flags of `args` that only `index-new` reads and that differ from their default.
+fn index_new_only_flags(args: &IndexArgs) -> Vec<&'static str> {
+    [
+        ("--pack-shards", args.pack_shards.is_some()),
+        ("--case-fold", args.case_fold),
+        ("--sparse", args.sparse),
+        ("--memory-limit", args.memory_limit.is_some()),
+        ("--max-file-size", args.max_file_size != DEFAULT_MAX_FILE_SIZE >> 10),
+        ("--max-line-length", args.max_line_length != DEFAULT_MAX_LINE_LEN),
+        ("--index-generated", args.index_generated),
+    ]
+    .into_iter()
+    .filter_map(|(flag, set)| set.then_some(flag))
+    .collect()
+}
+
 fn run_index(args: IndexArgs, verbose: bool) -> Result<()> {
+    let ignored = index_new_only_flags(&args);
+    if !ignored.is_empty() {
+        return Err(anyhow!("{} only apply to index-new", ignored.join(", ")));
+    }
     // 读取文件列表
-    let file_content = fs::read(args.file_list.clone())?;
-    let file_lines: Vec<String> = std::io::BufReader::new(&file_content[..])
-        .lines()
-        .filter_map(Result::ok)
-        .filter(|file| !file.is_empty())
-        .map(|line| line.trim().to_string())
-        .collect();
+    let file_lines = index_files(&args)?;
 
     // 获取总文件数
     let total_files = file_lines.len();
@@ -256,78 +505,289 @@ fn run_index(args: IndexArgs, verbose: bool) -> Result<()> {
 }
 
 fn run_search_new(args: SearchArgs, verbose: bool) -> Result<()> {
+    let search_start = Instant::now();
+    let base_path = &PathBuf::from_str(args.config.as_str())?;
+    let search_one_engine = search_one_engine(&args, base_path)?;
+    let ngrams_path = search_one_engine.ngrams();
+    info!("Need get {} ngrams.", ngrams_path.0.len());
+    debug!("Need get ngrams {:?}", ngrams_path);
+    let ngarm_index_data = read_ngrams(base_path, &search_one_engine, &ngrams_path)?;
+
+    debug!("Get ngrams data {:?}", ngarm_index_data.len());
+    let files_lines_index = search_one_engine.files_lines(ngrams_path, ngarm_index_data)?;
+    debug!("Get files lines index {:?}", files_lines_index);
+
+    if files_lines_index.is_all() {
+        info!("Pattern has no usable ngram, scan all files.");
+    }
+    let show_terms = search_one_engine.terms_len() > 1;
+    let mut json = args.json.then(JsonPrinter::default);
+    let mut page = match args.page {
+        Some(page) => {
+            let definitions = match &args.lsp_index {
+                Some(lsp_index) => read_definition_lines(
+                    Path::new(lsp_index),
+                    &search_one_engine,
+                    &files_lines_index,
+                )?,
+                None => DefinitionLines::default(),
+            };
+            let page = search_one_engine.ranked_page(
+                &files_lines_index,
+                &definitions,
+                page - 1,
+                args.page_size,
+            )?;
+            Some(page)
+        }
+        None => None,
+    };
+    let mut offset = 0;
+    loop {
+        let files = match args.page {
+            Some(_) => page.take().unwrap_or_default(),
+            None => search_one_engine.files_batch(&files_lines_index, offset, SEARCH_BATCH)?,
+        };
+        if files.is_empty() {
+            break;
+        }
+        offset += files.len();
+        let files_data = files
+            .into_par_iter()
+            .map(|file_index| {
+                let start = Instant::now();
+                read_file(&file_index.path(base_path).join("file"))
+                    .map_err(|e| {
+                        anyhow!(
+                            "Failed to read file data for file id {} ({}): {:?}",
+                            file_index.file_id(),
+                            search_one_engine.path(&file_index).unwrap_or_default(),
+                            e
+                        )
+                    })
+                    .and_then(|data| match (args.files_with_matches, args.count) {
+                        (true, _) => search_one_engine
+                            .file_is_match(file_index, data, &files_lines_index)
+                            .map(FileOutput::Matched),
+                        (false, true) => search_one_engine
+                            .file_match_count(file_index, data, &files_lines_index)
+                            .map(FileOutput::Count),
+                        (false, false) => search_one_engine
+                            .file_lines_match(file_index, data, &files_lines_index)
+                            .map(FileOutput::Lines),
+                    })
+                    .map(|output| (file_index, output, start.elapsed()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        files_data
+            .into_iter()
+            .try_for_each(|(file_index, output, elapsed)| {
+                // Names come from the path table, a file blob is only read to verify the match.
+                let path = || search_one_engine.path(&file_index).unwrap_or_default();
+                match (output, json.as_mut()) {
+                    (FileOutput::Lines(file_data), Some(json)) => json
+                        .file(file_data.full_file_name(), &file_data, elapsed)?
+                        .iter()
+                        .for_each(|line| println!("{}", line)),
+                    (FileOutput::Lines(file_data), None) if args.vimgrep => {
+                        print_vimgrep(&file_data)
+                    }
+                    (FileOutput::Lines(file_data), None) => {
+                        print_file_lines(file_index, &file_data, show_terms)
+                    }
+                    (FileOutput::Count(count), _) if count > 0 => {
+                        println!("{}:{}", path().purple(), count)
+                    }
+                    (FileOutput::Matched(true), _) => println!("{}", path().purple()),
+                    _ => info!("file {} not match", file_index.file_id()),
+                }
+                Ok::<_, anyhow::Error>(())
+            })?;
+    }
+    if let Some(json) = json {
+        println!("{}", json.summary(search_start.elapsed())?);
+    }
+    Ok(())
+}
+
+/// Definition lines of every candidate file indexed by `clang-index`, files
+/// the LSP index does not know are skipped.
+fn read_definition_lines(
+    lsp_base: &Path,
+    search_one_engine: &SearchOneEngine,
+    result: &ngram::search::SearchOneQueryResult,
+) -> Result<DefinitionLines> {
+    use crate::lsp::data::FromToData;
+    use crate::lsp::path::GetPath;
+    let datas = search_one_engine
+        .files(result)?
+        .into_par_iter()
+        .filter_map(|file_index| search_one_engine.path(&file_index))
+        .filter_map(|path| {
+            let file_index = lsp::index::FileIndex::from(path);
+            let path = lsp::path::DefinitionDataPath::from(&file_index)
+                .path(lsp_base)
+                .join("definition.data");
+            path.exists().then(|| {
+                read_file(&path)
+                    .map_err(|e| anyhow!("Failed to read definition data {:?}: {}", path, e))
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    datas
+        .iter()
+        .try_fold(DefinitionLines::default(), |mut definitions, data| {
+            definitions.insert(&lsp::data::DefinitionsData::from_data(data)?);
+            Ok(definitions)
+        })
+}
+
+/// The engine of `args.search_term` over the index in `base_path`.
+fn search_one_engine(args: &SearchArgs, base_path: &Path) -> Result<SearchOneEngine> {
     info!("Using config directory: {}", args.config);
     info!("Search term: {}", args.search_term);
-    let base_path = &PathBuf::from_str(args.config.as_str())?;
     let global_data_path = GlobalDataPath::from(());
     let data = read_file(&global_data_path.path(base_path))?;
     let global_data = GlobalData::from_data(data.as_slice())?;
-    let search_engine = SearchEngine::from(global_data);
-    let search_one_engine = search_engine.search(args.search_term.as_str())?;
-    let ngrams_path = search_one_engine.ngrams();
-    info!("Need get {} ngrams.", ngrams_path.0.len());
-    debug!("Need get ngrams {:?}", ngrams_path);
+    let header = global_data.header();
+    info!(
+        "Index built by igrep {} (format {}) at {} with {} files, {}.",
+        header.tool_version(),
+        header.format_version(),
+        header.created(),
+        header.file_count(),
+        global_data.skipped()
+    );
+    let data = read_file(&PathTablePath::from(()).path(base_path))?;
+    let path_table = PathTable::from_data(data.as_slice())?;
+    let search_engine = SearchEngine::from(global_data)
+        .with_scan_limit(args.scan_limit)
+        .with_path_table(path_table);
+    let options = args.glob.iter().try_fold(SearchOptions::default(), |options, glob| {
+        let filter = match glob.strip_prefix('!') {
+            Some(glob) => PathFilter::try_from(format!("-glob:{}", glob).as_str()),
+            None => PathFilter::try_from(format!("glob:{}", glob).as_str()),
+        };
+        filter.map(|filter| options.with_path_filter(filter))
+    })?;
+    let options = options
+        .with_ignore_case(args.ignore_case)
+        .with_fixed_strings(args.fixed_strings)
+        .with_query(args.query)
+        .with_multiline(args.multiline)
+        .with_max_count(args.max_count)
+        .with_context(
+            args.before_context.or(args.context).unwrap_or(0),
+            args.after_context.or(args.context).unwrap_or(0),
+        );
+    search_engine.search_with(args.search_term.as_str(), &options)
+}
 
-    let ngarm_index_data = ngrams_path
+/// Posting lists of `ngrams_path`, from the pack shards when the index has them.
+fn read_ngrams(
+    base_path: &Path,
+    search_one_engine: &SearchOneEngine,
+    ngrams_path: &NgramIndexVec,
+) -> Result<Vec<Vec<u8>>> {
+    ngrams_path
         .0
         .iter()
-        .map(|ngram_path| {
-            read_file(&ngram_path.path(base_path)).map_err(|e| {
+        .map(|ngram_path| match search_one_engine.pack_range(ngram_path) {
+            Some(pack_range) => {
+                NgramShardPath::from(&pack_range).read(base_path, pack_range.range())
+            }
+            None => read_file(&ngram_path.path(base_path)).map_err(|e| {
                 anyhow!(
                     "Failed to read ngram data for ngram {:?}: {}",
                     ngram_path,
                     e
                 )
-            })
+            }),
         })
-        .collect::<Result<Vec<_>>>()?;
-
-    debug!("Get ngrams data {:?}", ngarm_index_data.len());
-    let files_lines_index = search_one_engine.files_lines(ngrams_path, ngarm_index_data)?;
-    debug!("Get files lines index {:?}", files_lines_index);
+        .collect::<Result<Vec<_>>>()
+}
 
-    let files_data = files_lines_index
-        .files()?
-        .into_iter()
-        .map(|file_index| {
-            read_file(&file_index.path(base_path).join("file"))
-                .map_err(|e| {
-                    anyhow!(
-                        "Failed to read file data for file id {}: {:?}",
-                        file_index.file_id(),
-                        e
-                    )
-                })
-                .and_then(|d| Ok((file_index, d)))
-        })
-        .collect::<Result<Vec<_>>>()?;
+fn run_explain(args: SearchArgs) -> Result<()> {
+    let base_path = &PathBuf::from_str(args.config.as_str())?;
+    let search_one_engine = search_one_engine(&args, base_path)?;
+    let ngrams_path = search_one_engine.ngrams();
+    let ngarm_index_data = read_ngrams(base_path, &search_one_engine, &ngrams_path)?;
+    println!("{}", search_one_engine.explain(ngrams_path, ngarm_index_data)?);
+    Ok(())
+}
 
-    files_data.into_iter().try_for_each(|(file_index, data)| {
-        let file_data = search_one_engine.file_lines_match(file_index, data, &files_lines_index)?;
-        match file_data.is_empty() {
-            true => info!("file {} not match", file_index.file_id()),
-            false => {
-                println!("{}", file_data.full_file_name().purple());
-                file_data.lines().iter().for_each(|line| {
-                    let line_num = line.line_num().to_string().green();
-                    print!("{}: ", line_num);
-                    let content = line.content();
-                    let match_ranges = line.match_range();
-                    let mut last_index = 0;
-                    for (start, end) in match_ranges {
-                        let before = &content[last_index..*start as usize];
-                        let matched = &content[*start as usize..*end as usize].red();
-                        print!("{}{}", before, matched);
-                        last_index = *end as usize;
+fn print_file_lines(
+    file_index: FileIndex,
+    file_data: &SearchOneFileLinesContentResult,
+    show_terms: bool,
+) {
+    match file_data.is_empty() {
+        true => info!("file {} not match", file_index.file_id()),
+        false => {
+            debug!(
+                "file {} matched {} lines",
+                file_index.file_id(),
+                file_data.lines().len()
+            );
+            println!("{}", file_data.full_file_name().purple());
+            file_data
+                .groups()
+                .iter()
+                .enumerate()
+                .for_each(|(i, group)| {
+                    if i > 0 {
+                        println!("{}", "--".blue());
                     }
-                    let after = &content[last_index..];
-                    println!("{}", after);
+                    group.iter().for_each(|line| print_line(line, show_terms));
                 });
-            }
         }
-        Ok::<(), anyhow::Error>(())
-    })?;
-    Ok(())
+    }
+}
+
+/// One `path:line:column:content` row per match, the quickfix format of Vim and Emacs.
+fn print_vimgrep(file_data: &SearchOneFileLinesContentResult) {
+    file_data.lines().iter().for_each(|line| {
+        line.match_columns().iter().for_each(|(column, _)| {
+            println!(
+                "{}:{}:{}:{}",
+                file_data.full_file_name(),
+                line.line_num(),
+                column,
+                line.content()
+            )
+        })
+    });
+}
+
+/// `N: ` before matched lines, `N- ` before context lines, like grep.
+/// With `show_terms` the matching query terms follow the line number, `N[1,3]: `.
+fn print_line(line: &SearchOneLineContentResult, show_terms: bool) {
+    let line_num = line.line_num().to_string().green();
+    match (line.is_context(), show_terms) {
+        (true, _) => print!("{}- ", line_num),
+        (false, true) => {
+            let terms = line
+                .terms()
+                .iter()
+                .map(|term| (term + 1).to_string())
+                .collect::<Vec<_>>();
+            print!("{}[{}]: ", line_num, terms.join(","))
+        }
+        (false, false) => print!("{}: ", line_num),
+    }
+    let content = line.content();
+    let match_ranges = line.match_range();
+    let mut last_index = 0;
+    for (start, end) in match_ranges {
+        let (start, end) = (*start as usize, *end as usize);
+        let (Some(before), Some(matched)) = (content.get(last_index..start), content.get(start..end))
+        else {
+            continue;
+        };
+        print!("{}{}", before, matched.red());
+        last_index = end;
+    }
+    println!("{}", content.get(last_index..).unwrap_or_default());
 }
 
 fn run_search(args: SearchArgs, verbose: bool) -> Result<()> {
@@ -1,21 +1,40 @@
-use crate::ngram::data::{GlobalData, NgramData};
-use crate::ngram::path::{FilePath, GlobalDataPath};
+use crate::ngram::data::{
+    FileData, FileMeta, FilesMetaData, FromToData, GlobalData, IndexHeader, NgramData, NgramPack,
+    NgramPackRange, PathTable,
+};
+use crate::ngram::path::{
+    FilePath, FilesMetaPath, GetPath, GlobalDataPath, NgramShardPath, PathTablePath,
+};
 use crate::ngram::{
+    external::{MAX_MERGE_RUNS, Run, RunEntry, RunMerger},
+    encoding::{TextEncoding, crlf_lines, split_lines},
+    filter::{FileFilter, SkipReason, SkipSummary},
     index::{
         FileIndex, FileLinesIndex, FilesLinesIndex, LineIndex, LinesIndex, NgramIndex,
-        NgramIndexVec,
+        NgramIndexVec, NgramShape, SetCalculate,
     },
     path::NgramPath,
 };
+use crate::range::{Offset, Range};
 use anyhow::{Error, Result, anyhow};
-use log::{info, warn};
+use log::{debug, info, warn};
 use rayon::prelude::*;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::io::Write;
 use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// Files read and indexed at a time by `Builder::index_streaming`.
+const STREAM_BATCH: usize = 256;
 
 pub struct Builder {
     ngram_len: u8,
+    pack_shards: Option<u32>,
+    case_fold: bool,
+    sparse: bool,
+    filter: FileFilter,
+    skipped: SkipSummary,
     ngram_to_files_lines: HashMap<NgramIndex, FilesLinesIndex>,
     file_id_to_content: HashMap<FileIndex, FileContent>,
 }
@@ -26,6 +45,7 @@ pub struct FileIndexBuilder {
 
 pub struct FileIndexFinalBuilder {
     files: Vec<(FileIndex, FileContent)>,
+    skipped: SkipSummary,
 }
 
 #[derive(Clone, Hash, Eq, PartialEq)]
@@ -35,7 +55,27 @@ pub struct AbsPath {
 
 pub struct FileContent {
     full_file_name: AbsPath,
-    lines: Vec<String>,
+    lines: Vec<Vec<u8>>,
+    encoding: TextEncoding,
+    crlf_lines: Vec<u32>,
+    mtime: u64,
+    hash: u64,
+    size: u64,
+}
+
+/// Re-index an existing dump in place, only touching the ngrams and files
+/// that changed since the last build.
+pub struct UpdateBuilder {
+    global_data: GlobalData,
+    files_meta: HashMap<AbsPath, FileMeta>,
+    next_file_id: u32,
+}
+
+enum UpdateFile {
+    Unchanged(FileMeta),
+    Touched(FileMeta),
+    Changed(FileIndex, FileContent),
+    Added(FileContent),
 }
 
 pub struct BuilderOneIndex {
@@ -53,16 +93,54 @@ impl Builder {
         } else {
             Ok(Self {
                 ngram_len,
+                pack_shards: None,
+                case_fold: false,
+                sparse: false,
+                filter: FileFilter::default(),
+                skipped: SkipSummary::default(),
                 ngram_to_files_lines: HashMap::new(),
                 file_id_to_content: HashMap::new(),
             })
         }
     }
 
+    /// Dump posting lists into `shards` pack files instead of one file per ngram.
+    pub fn with_pack_shards(mut self, shards: Option<u32>) -> Result<Self> {
+        if shards == Some(0) {
+            return Err(anyhow!("Pack shards must be greater than 0"));
+        }
+        self.pack_shards = shards;
+        Ok(self)
+    }
+
+    /// Also index the lowercased lines, for case insensitive searches.
+    pub fn with_case_fold(mut self, case_fold: bool) -> Self {
+        self.case_fold = case_fold;
+        self
+    }
+
+    /// Index sparse ngrams of 3 up to `ngram_len` bytes, see `NgramShape::Sparse`.
+    pub fn with_sparse(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    /// Files read by `index_streaming` are classified by `filter`.
+    pub fn with_file_filter(mut self, filter: FileFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Files left out by the `FileIndexFinalBuilder` indexed last.
+    pub fn skipped(&self) -> &SkipSummary {
+        &self.skipped
+    }
+
     pub fn index(&mut self, file_builder: FileIndexFinalBuilder) -> Result<()> {
         info!("start index files...");
-        let all_builders = file_builder
-            .files
+        let FileIndexFinalBuilder { files, skipped } = file_builder;
+        self.skipped = skipped;
+        let all_builders = files
             .into_par_iter()
             .map(|(file_id, file_content)| self.index_one(file_id, file_content))
             .collect::<Vec<_>>();
@@ -74,20 +152,160 @@ impl Builder {
 
     pub fn dump(&self, base_path: &Path) -> Result<()> {
         self.remove_old_dump(base_path)?;
-        self.dump_global(base_path)?;
-        self.dump_ngrams(base_path)?;
+        match self.pack_shards {
+            Some(shards) => self.dump_packed(base_path, shards)?,
+            None => {
+                self.dump_global(base_path)?;
+                self.dump_ngrams(base_path)?;
+            }
+        }
         self.dump_files(base_path)?;
+        self.dump_files_meta(base_path)?;
+        self.dump_path_table(base_path)?;
         Ok(())
     }
 }
 
 impl Builder {
+    /// Index and dump `files` with memory bounded by about `memory_limit`
+    /// bytes. File blobs are written batch by batch, posting entries are
+    /// spilled to sorted runs under `base_path` once they take half of the
+    /// limit, then merged straight into posting lists. A batch only takes
+    /// the files whose entries fit in the rest of that half. Returns the
+    /// files `filter` left out.
+    pub fn index_streaming(
+        &self,
+        files: FileIndexBuilder,
+        base_path: &Path,
+        memory_limit: usize,
+    ) -> Result<SkipSummary> {
+        self.remove_old_dump(base_path)?;
+        let runs_path = base_path.join("runs");
+        std::fs::create_dir_all(&runs_path)
+            .map_err(|e| anyhow!("Failed to create {:?}: {}", runs_path, e))?;
+        let mut files = files
+            .file_to_id
+            .into_iter()
+            .map(|(path, file_id)| (file_id, path))
+            .collect::<Vec<_>>();
+        files.sort_by_key(|(file_id, _)| *file_id);
+
+        let budget = memory_limit / 2;
+        let mut pending = files
+            .iter()
+            .map(|(file_id, path)| (file_id, path, self.entries_estimate(path)))
+            .peekable();
+        let mut metas = vec![];
+        let mut runs = vec![];
+        let mut entries: Vec<RunEntry> = vec![];
+        let mut ngrams_size = 0;
+        let mut skipped = SkipSummary::default();
+        while pending.peek().is_some() {
+            let held = entries.capacity() * std::mem::size_of::<RunEntry>() + ngrams_size;
+            let remaining = budget.saturating_sub(held);
+            let (mut batch, mut estimate) = (vec![], 0);
+            while let Some((file_id, path, size)) = pending.next_if(|(_, _, size)| {
+                batch.is_empty() || (batch.len() < STREAM_BATCH && estimate + size <= remaining)
+            }) {
+                estimate += size;
+                batch.push((*file_id, path.clone()));
+            }
+            let indexed = batch
+                .par_iter()
+                .map(|(file_id, path)| match FileContent::read(path.clone(), &self.filter) {
+                    Ok(content) => {
+                        FilePath::from(file_id).dump(base_path, &content)?;
+                        let meta = FileMeta::from((*file_id, &content));
+                        Ok(Ok((meta, self.run_entries(*file_id, &content))))
+                    }
+                    Err(reason) => Ok(Err(reason)),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            for file in indexed {
+                let (meta, file_entries) = match file {
+                    Ok(file) => file,
+                    Err(reason) => {
+                        skipped.insert(reason);
+                        continue;
+                    }
+                };
+                metas.push(meta);
+                ngrams_size += file_entries.iter().map(RunEntry::heap_size).sum::<usize>();
+                entries.extend(file_entries);
+                if entries.capacity() * std::mem::size_of::<RunEntry>() + ngrams_size >= budget {
+                    let path = runs_path.join(runs.len().to_string());
+                    runs.push(RunEntry::spill(&path, &mut entries)?);
+                    ngrams_size = 0;
+                }
+            }
+            info!("indexed {} files, spilled {} runs.", metas.len(), runs.len());
+        }
+        if !entries.is_empty() {
+            let path = runs_path.join(runs.len().to_string());
+            runs.push(RunEntry::spill(&path, &mut entries)?);
+        }
+
+        info!("start merging {} runs...", runs.len());
+        let runs = Run::reduce(&runs_path, runs, MAX_MERGE_RUNS)?;
+        let header = IndexHeader::from((self.ngram_len, metas.len() as u32));
+        let mut global_data = match self.pack_shards {
+            Some(shards) => GlobalData::from((header, merge_runs_packed(base_path, &runs, shards)?)),
+            None => GlobalData::from((header, merge_runs(base_path, &runs, budget)?)),
+        };
+        global_data.set_files(metas.iter().map(|meta| meta.file_index()));
+        global_data.set_case_fold(self.case_fold);
+        global_data.set_sparse(self.sparse);
+        global_data.set_filter(self.filter);
+        global_data.set_skipped(skipped.clone());
+        GlobalDataPath::from(()).dump(base_path, &global_data)?;
+        std::fs::remove_dir_all(&runs_path)
+            .map_err(|e| anyhow!("Failed to remove {:?}: {}", runs_path, e))?;
+
+        let path_table = PathTable::from(
+            metas
+                .iter()
+                .map(|meta| (meta.file_index(), meta.full_file_name().to_string()))
+                .collect::<Vec<_>>(),
+        );
+        PathTablePath::from(()).dump(base_path, &path_table)?;
+        FilesMetaPath::from(()).dump(base_path, &FilesMetaData::from(metas))?;
+        info!("merge runs finish.");
+        Ok(skipped)
+    }
+
+    /// Memory of the run entries of `path` by its size, nothing for files
+    /// `filter` skips unread.
+    fn entries_estimate(&self, path: &AbsPath) -> usize {
+        let size = std::fs::metadata(&path.path)
+            .map(|meta| meta.len())
+            .unwrap_or(0);
+        match self.filter.oversized(size) {
+            true => 0,
+            false => size as usize * RunEntry::bytes_per_source_byte(self.shape(), self.case_fold),
+        }
+    }
+
+    fn run_entries(&self, file_id: FileIndex, file_content: &FileContent) -> Vec<RunEntry> {
+        file_content
+            .lines
+            .iter()
+            .enumerate()
+            .flat_map(|(id, line)| {
+                let ngrams = NgramIndexVec::from((line.as_slice(), self.shape(), self.case_fold));
+                ngrams
+                    .0
+                    .into_iter()
+                    .map(move |ngram| RunEntry::from((ngram, file_id, LineIndex::from(id as u32))))
+            })
+            .collect()
+    }
+
     fn index_one(&self, file_id: FileIndex, file_content: FileContent) -> BuilderOneIndex {
         let mut ngram_to_file_line: HashMap<NgramIndex, Vec<LineIndex>> = HashMap::new();
         file_content
             .lines
             .iter()
-            .map(|line| NgramIndexVec::from((line.as_bytes(), self.ngram_len)))
+            .map(|line| NgramIndexVec::from((line.as_slice(), self.shape(), self.case_fold)))
             .enumerate()
             .map(|(id, ngrams)| (LineIndex::from((id) as u32), ngrams))
             .map(|(lid, ngrams)| ngrams.0.into_iter().map(move |ngram| (ngram, lid.clone())))
@@ -123,13 +341,13 @@ impl Builder {
                                 old_content.full_file_name.path
                             ))
                         })
-                        .and_then(move |()| {
-                            Ok(ngram_to_line.into_iter().map(move |(ngram, line_ids)| {
+                        .map(move |()| {
+                            ngram_to_line.into_iter().map(move |(ngram, line_ids)| {
                                 (
                                     ngram,
                                     FileLinesIndex::from((file_id, LinesIndex::from(line_ids))),
                                 )
-                            }))
+                            })
                         })
                 },
             )
@@ -168,6 +386,36 @@ impl Builder {
         info!("dump ngrams finish.");
         Ok(())
     }
+    fn files(&self) -> impl Iterator<Item = (FileIndex, String)> + '_ {
+        self.file_id_to_content
+            .iter()
+            .map(|(file_id, content)| (*file_id, content.get_full_file_name().to_string()))
+    }
+    fn shape(&self) -> NgramShape {
+        match self.sparse {
+            true => NgramShape::Sparse(self.ngram_len),
+            false => NgramShape::Fixed(self.ngram_len),
+        }
+    }
+    fn header(&self) -> IndexHeader {
+        IndexHeader::from((self.ngram_len, self.file_id_to_content.len() as u32))
+    }
+    fn dump_packed(&self, base_path: &Path, shards: u32) -> Result<()> {
+        info!("start dump ngrams into {} packs...", shards);
+        let pack = dump_pack(base_path, &self.ngram_to_files_lines, shards)?;
+        let mut global_data = GlobalData::from((self.header(), pack));
+        global_data.set_files(self.files().map(|(file_index, _)| file_index));
+        global_data.set_case_fold(self.case_fold);
+        global_data.set_sparse(self.sparse);
+        global_data.set_filter(self.filter);
+        global_data.set_skipped(self.skipped.clone());
+        GlobalDataPath::from(()).dump(base_path, &global_data)?;
+        info!(
+            "dump packs finish. Include {} ngrams.",
+            self.ngram_to_files_lines.len()
+        );
+        Ok(())
+    }
     fn dump_files(&self, base_path: &Path) -> Result<()> {
         info!("start dump files...");
         self.file_id_to_content
@@ -179,14 +427,31 @@ impl Builder {
         info!("dump files finish.");
         Ok(())
     }
+    fn dump_files_meta(&self, base_path: &Path) -> Result<()> {
+        let files_meta = self
+            .file_id_to_content
+            .iter()
+            .map(|(file_id, file_content)| FileMeta::from((*file_id, file_content)))
+            .collect::<Vec<_>>();
+        FilesMetaPath::from(()).dump(base_path, &FilesMetaData::from(files_meta))
+    }
+    fn dump_path_table(&self, base_path: &Path) -> Result<()> {
+        let path_table = PathTable::from(self.files().collect::<Vec<_>>());
+        PathTablePath::from(()).dump(base_path, &path_table)
+    }
     fn dump_global(&self, base_path: &Path) -> Result<()> {
         info!("start dump global data...");
         let index = self
             .ngram_to_files_lines
-            .keys()
-            .cloned()
-            .collect::<HashSet<NgramIndex>>();
-        let global_data = GlobalData::from((self.ngram_len, index));
+            .iter()
+            .map(|(ngram, files_lines)| (ngram.clone(), files_lines.files_lines().len() as u32))
+            .collect::<HashMap<NgramIndex, u32>>();
+        let mut global_data = GlobalData::from((self.header(), index));
+        global_data.set_files(self.files().map(|(file_index, _)| file_index));
+        global_data.set_case_fold(self.case_fold);
+        global_data.set_sparse(self.sparse);
+        global_data.set_filter(self.filter);
+        global_data.set_skipped(self.skipped.clone());
         let global_path = GlobalDataPath::from(());
         global_path.dump(base_path, &global_data)?;
         let index_num = self.ngram_to_files_lines.len();
@@ -195,6 +460,427 @@ impl Builder {
     }
 }
 
+impl UpdateBuilder {
+    pub fn load(base_path: &Path) -> Result<Self> {
+        let global_path = GlobalDataPath::from(()).path(base_path);
+        let data = std::fs::read(&global_path)
+            .map_err(|e| anyhow!("Failed to read {:?}: {}", global_path, e))?;
+        let global_data = GlobalData::from_data(&data)?;
+        let meta_path = FilesMetaPath::from(()).path(base_path);
+        let data = std::fs::read(&meta_path).map_err(|e| {
+            anyhow!(
+                "Failed to read {:?}: {}, rebuild the index with index-new first",
+                meta_path,
+                e
+            )
+        })?;
+        let files_meta = FilesMetaData::from_data(&data)?;
+        let next_file_id = files_meta
+            .max_file_index()
+            .map_or(0, |file_index| file_index.file_id() + 1);
+        let files_meta = files_meta
+            .files()
+            .iter()
+            .map(|meta| (AbsPath::from(meta.full_file_name().to_string()), meta.clone()))
+            .collect::<HashMap<_, _>>();
+        Ok(Self {
+            global_data,
+            files_meta,
+            next_file_id,
+        })
+    }
+
+    /// Returns the files of `files_name_list` left out of the index, by
+    /// the filter the index was built with.
+    pub fn update(mut self, base_path: &Path, files_name_list: Vec<String>) -> Result<SkipSummary> {
+        let new_paths = files_name_list
+            .into_iter()
+            .map(AbsPath::from)
+            .collect::<HashSet<_>>();
+        let (files, skipped): (Vec<_>, Vec<_>) = new_paths
+            .into_par_iter()
+            .map(|path| self.classify(path))
+            .partition_map(|file| match file {
+                Ok(file) => rayon::iter::Either::Left(file),
+                Err(reason) => rayon::iter::Either::Right(reason),
+            });
+        let skipped = skipped.into_iter().collect::<SkipSummary>();
+
+        let mut metas = Vec::new();
+        let mut changed = Vec::new();
+        let mut added = Vec::new();
+        files.into_iter().for_each(|file| match file {
+            UpdateFile::Unchanged(meta) | UpdateFile::Touched(meta) => metas.push(meta),
+            UpdateFile::Changed(file_id, content) => changed.push((file_id, content)),
+            UpdateFile::Added(content) => added.push(content),
+        });
+        // Files missing from the new list or no longer readable are removed.
+        let kept = metas
+            .iter()
+            .map(|meta| meta.file_index())
+            .chain(changed.iter().map(|(file_id, _)| *file_id))
+            .collect::<HashSet<_>>();
+        let removed = self
+            .files_meta
+            .values()
+            .map(|meta| meta.file_index())
+            .filter(|file_id| !kept.contains(file_id))
+            .collect::<HashSet<_>>();
+        added.sort_by(|a, b| a.full_file_name.path.cmp(&b.full_file_name.path));
+        let added = added
+            .into_iter()
+            .map(|content| {
+                let file_id = FileIndex::from(self.next_file_id);
+                self.next_file_id += 1;
+                (file_id, content)
+            })
+            .collect::<Vec<_>>();
+        info!(
+            "update: {} unchanged, {} changed, {} added, {} removed.",
+            metas.len(),
+            changed.len(),
+            added.len(),
+            removed.len()
+        );
+
+        let stale = changed
+            .iter()
+            .map(|(file_id, _)| *file_id)
+            .chain(removed.iter().cloned())
+            .collect::<HashSet<_>>();
+        let old_ngrams = self.stale_ngrams(base_path, &stale)?;
+
+        let ngram_len = self.global_data.ngram_len();
+        let mut builder = Builder::new(ngram_len)?
+            .with_case_fold(self.global_data.case_fold())
+            .with_sparse(matches!(self.global_data.ngram_shape(), NgramShape::Sparse(_)));
+        let files = changed.into_iter().chain(added).collect::<Vec<_>>();
+        builder.index(FileIndexFinalBuilder {
+            files,
+            skipped: SkipSummary::default(),
+        })?;
+        metas.extend(
+            builder
+                .file_id_to_content
+                .iter()
+                .map(|(file_id, content)| FileMeta::from((*file_id, content))),
+        );
+
+        let affected = old_ngrams
+            .into_iter()
+            .chain(builder.ngram_to_files_lines.keys().cloned())
+            .collect::<HashSet<_>>();
+        info!("update: rewrite {} ngrams.", affected.len());
+        let updated = affected
+            .into_par_iter()
+            .map(|ngram| {
+                let old = self.load_ngram(base_path, &ngram)?;
+                let files_lines = old.clone().map_or_else(
+                    || FilesLinesIndex::from(vec![]),
+                    |old| old.remove_files(&stale),
+                );
+                let files_lines = match builder.ngram_to_files_lines.get(&ngram) {
+                    Some(new) => FilesLinesIndex::union(files_lines, new.clone()),
+                    None => files_lines,
+                };
+                // Unchanged posting lists keep their file or pack range.
+                Ok((old.as_ref() != Some(&files_lines)).then_some((ngram, files_lines)))
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        match self.global_data.pack().cloned() {
+            Some(pack) => {
+                let pack = append_pack(base_path, pack, updated)?;
+                self.global_data.set_pack(pack);
+            }
+            None => {
+                let ngrams_exist = updated
+                    .into_par_iter()
+                    .map(|(ngram, files_lines)| self.dump_ngram(base_path, ngram, files_lines))
+                    .collect::<Result<Vec<_>>>()?;
+                ngrams_exist
+                    .into_iter()
+                    .for_each(|(ngram, files)| match files {
+                        Some(files) => self.global_data.insert_index(ngram, files),
+                        None => self.global_data.remove_index(&ngram),
+                    });
+            }
+        }
+
+        removed
+            .iter()
+            .try_for_each(|file_id| FilePath::from(file_id).remove(base_path))?;
+        builder.dump_files(base_path)?;
+        self.global_data
+            .set_files(metas.iter().map(|meta| meta.file_index()));
+        self.global_data.set_skipped(skipped.clone());
+        let path_table = PathTable::from(
+            metas
+                .iter()
+                .map(|meta| (meta.file_index(), meta.full_file_name().to_string()))
+                .collect::<Vec<_>>(),
+        );
+        GlobalDataPath::from(()).dump(base_path, &self.global_data)?;
+        PathTablePath::from(()).dump(base_path, &path_table)?;
+        FilesMetaPath::from(()).dump(base_path, &FilesMetaData::from(metas))?;
+        Ok(skipped)
+    }
+}
+
+impl UpdateBuilder {
+    fn classify(&self, path: AbsPath) -> Result<UpdateFile, SkipReason> {
+        let filter = self.global_data.filter();
+        match self.files_meta.get(&path) {
+            Some(meta) if file_stat(&path.path).ok() == Some((meta.mtime(), meta.size())) => {
+                Ok(UpdateFile::Unchanged(meta.clone()))
+            }
+            Some(meta) => {
+                let content = FileContent::read(path, filter)?;
+                let new_meta = FileMeta::from((meta.file_index(), &content));
+                match new_meta.same_content(meta) {
+                    true => Ok(UpdateFile::Touched(new_meta)),
+                    false => Ok(UpdateFile::Changed(meta.file_index(), content)),
+                }
+            }
+            None => Ok(UpdateFile::Added(FileContent::read(path, filter)?)),
+        }
+    }
+
+    /// Ngrams of the dumped content of `stale` files, read back from their file blobs.
+    fn stale_ngrams(
+        &self,
+        base_path: &Path,
+        stale: &HashSet<FileIndex>,
+    ) -> Result<HashSet<NgramIndex>> {
+        let shape = self.global_data.ngram_shape();
+        let case_fold = self.global_data.case_fold();
+        let ngrams = stale
+            .par_iter()
+            .map(|file_id| {
+                let path = FilePath::from(file_id).path(base_path).join("file");
+                let data = std::fs::read(&path)
+                    .map_err(|e| anyhow!("Failed to read {:?}: {}", path, e))?;
+                let file_data = FileData::from_data(&data)?;
+                Ok(file_data
+                    .all_lines()
+                    .iter()
+                    .flat_map(|line| NgramIndexVec::from((line.as_slice(), shape, case_fold)).0)
+                    .collect::<HashSet<_>>())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ngrams.into_iter().flatten().collect())
+    }
+
+    fn load_ngram(&self, base_path: &Path, ngram: &NgramIndex) -> Result<Option<FilesLinesIndex>> {
+        if let Some(pack_range) = self.global_data.pack_range(ngram) {
+            let shard = NgramShardPath::from(pack_range.shard());
+            let data = shard.read(base_path, pack_range.range())?;
+            return Ok(Some(NgramData::from_data(&data)?.files_lines()));
+        }
+        match self.global_data.has_index(ngram) {
+            true => {
+                let path = NgramPath::from(ngram).path(base_path);
+                let data = std::fs::read(&path)
+                    .map_err(|e| anyhow!("Failed to read {:?}: {}", path, e))?;
+                Ok(Some(NgramData::from_data(&data)?.files_lines()))
+            }
+            false => Ok(None),
+        }
+    }
+
+    fn dump_ngram(
+        &self,
+        base_path: &Path,
+        ngram: NgramIndex,
+        files_lines: FilesLinesIndex,
+    ) -> Result<(NgramIndex, Option<u32>)> {
+        let ngram_path = NgramPath::from(&ngram);
+        let files = (!files_lines.is_empty()).then(|| files_lines.files_lines().len() as u32);
+        match (files, self.global_data.has_index(&ngram)) {
+            (Some(_), _) => ngram_path.dump(base_path, &NgramData::from(files_lines))?,
+            (None, true) => ngram_path.remove(base_path)?,
+            (None, false) => {}
+        }
+        Ok((ngram, files))
+    }
+}
+
+/// Merge `runs` into one file per ngram, returns the document frequency of
+/// every ngram. Posting lists are written in batches of about `budget` bytes.
+fn merge_runs(base_path: &Path, runs: &[Run], budget: usize) -> Result<HashMap<NgramIndex, u32>> {
+    // Every merged entry adds at most one line and one file to its posting list.
+    let entry_size = std::mem::size_of::<LineIndex>() + std::mem::size_of::<FileLinesIndex>();
+    let mut merger = RunMerger::open(runs)?;
+    let mut indexs = HashMap::new();
+    let mut finished = false;
+    while !finished {
+        let (start, mut batch) = (merger.merged(), vec![]);
+        while batch.is_empty() || ((merger.merged() - start) as usize * entry_size) < budget {
+            match merger.next().transpose()? {
+                Some(posting) => batch.push(posting),
+                None => {
+                    finished = true;
+                    break;
+                }
+            }
+        }
+        let files = batch
+            .into_par_iter()
+            .map(|(ngram, files_lines)| {
+                let files = files_lines.files_lines().len() as u32;
+                NgramPath::from(&ngram).dump(base_path, &NgramData::from(files_lines))?;
+                Ok((ngram, files))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        indexs.extend(files);
+    }
+    Ok(indexs)
+}
+
+/// Merge `runs` into exactly `shards` pack files. The total number of ngrams
+/// is unknown while merging, so shards are cut by their share of posting
+/// entries instead of ngrams.
+fn merge_runs_packed(base_path: &Path, runs: &[Run], shards: u32) -> Result<NgramPack> {
+    let total = runs.iter().map(Run::entries).sum::<u64>().max(1);
+    let create = |shard: u32| {
+        let path = NgramShardPath::from(shard).path(base_path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| anyhow!("Failed to create {:?}: {}", parent, e))?;
+        }
+        std::fs::File::create(&path)
+            .map(std::io::BufWriter::new)
+            .map_err(|e| anyhow!("Failed to create {:?}: {}", path, e))
+    };
+    let mut merger = RunMerger::open(runs)?;
+    let mut ranges = HashMap::new();
+    let (mut shard, mut writer, mut offset) = (0, create(0)?, 0);
+    loop {
+        let target = ((merger.merged() * shards as u64 / total) as u32).min(shards - 1);
+        let Some(next) = merger.next() else {
+            break;
+        };
+        let (ngram, files_lines) = next?;
+        while shard < target {
+            writer.flush()?;
+            shard += 1;
+            (writer, offset) = (create(shard)?, 0);
+        }
+        let files = files_lines.files_lines().len() as u32;
+        let bytes = NgramData::from(files_lines).to_data()?;
+        writer.write_all(&bytes)?;
+        let range = Range::new(offset as Offset, bytes.len() as u32);
+        offset += bytes.len();
+        ranges.insert(ngram, NgramPackRange::from((shard, range, files)));
+    }
+    writer.flush()?;
+    (shard + 1..shards).try_for_each(|shard| create(shard).map(drop))?;
+    Ok(NgramPack::from((shards, ranges)))
+}
+
+/// Split the sorted ngrams into exactly `shards` contiguous groups and write
+/// each group as one pack file.
+fn dump_pack(
+    base_path: &Path,
+    ngram_to_files_lines: &HashMap<NgramIndex, FilesLinesIndex>,
+    shards: u32,
+) -> Result<NgramPack> {
+    let mut ngrams = ngram_to_files_lines.keys().collect::<Vec<_>>();
+    ngrams.sort();
+    let ranges = (0..shards)
+        .into_par_iter()
+        .map(|shard| {
+            let start = ngrams.len() * shard as usize / shards as usize;
+            let end = ngrams.len() * (shard as usize + 1) / shards as usize;
+            let mut data = Vec::new();
+            let ranges = ngrams[start..end]
+                .iter()
+                .map(|ngram| {
+                    let files_lines = &ngram_to_files_lines[*ngram];
+                    let bytes = NgramData::from(files_lines.clone()).to_data()?;
+                    let range = Range::new(data.len() as Offset, bytes.len() as u32);
+                    data.extend(bytes);
+                    let files = files_lines.files_lines().len() as u32;
+                    Ok(((*ngram).clone(), NgramPackRange::from((shard, range, files))))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            NgramShardPath::from(shard).dump(base_path, &data)?;
+            Ok(ranges)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let ranges = ranges.into_iter().flatten().collect::<HashMap<_, _>>();
+    Ok(NgramPack::from((shards, ranges)))
+}
+
+/// Writes the `updated` posting lists of a packed index without moving the
+/// others: a changed list is appended to the end of its shard, a new ngram
+/// goes to the smallest shard and an empty list drops its range. The bytes of
+/// replaced lists stay in the shards until the next `index-new`, so cached
+/// ranges of unchanged ngrams stay valid.
+fn append_pack(
+    base_path: &Path,
+    pack: NgramPack,
+    mut updated: Vec<(NgramIndex, FilesLinesIndex)>,
+) -> Result<NgramPack> {
+    let shards = pack.shards();
+    let mut sizes = (0..shards)
+        .map(|shard| {
+            let path = NgramShardPath::from(shard).path(base_path);
+            std::fs::metadata(&path)
+                .map(|meta| meta.len())
+                .map_err(|e| anyhow!("Failed to stat {:?}: {}", path, e))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut appends = vec![Vec::new(); shards as usize];
+    let mut ranges = pack.ranges().clone();
+    updated.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (ngram, files_lines) in updated {
+        if files_lines.is_empty() {
+            ranges.remove(&ngram);
+            continue;
+        }
+        let shard = match ranges.get(&ngram) {
+            Some(pack_range) => pack_range.shard(),
+            None => (0..shards).min_by_key(|shard| sizes[*shard as usize]).unwrap_or(0),
+        };
+        let bytes = NgramData::from(files_lines.clone()).to_data()?;
+        let range = Range::new(sizes[shard as usize] as Offset, bytes.len() as u32);
+        sizes[shard as usize] = range.end();
+        appends[shard as usize].extend(bytes);
+        let files = files_lines.files_lines().len() as u32;
+        ranges.insert(ngram, NgramPackRange::from((shard, range, files)));
+    }
+    appends
+        .iter()
+        .enumerate()
+        .filter(|(_, data)| !data.is_empty())
+        .try_for_each(|(shard, data)| NgramShardPath::from(shard as u32).append(base_path, data))?;
+    Ok(NgramPack::from((shards, ranges)))
+}
+
+fn modified_time(path: &str) -> Result<u64> {
+    file_stat(path).map(|(mtime, _)| mtime)
+}
+
+/// Modification time in nanoseconds and length of the file at `path`.
+fn file_stat(path: &str) -> Result<(u64, u64)> {
+    let meta =
+        std::fs::metadata(path).map_err(|e| anyhow!("Failed to stat file {}: {}", path, e))?;
+    let modified = meta
+        .modified()
+        .map_err(|e| anyhow!("Failed to stat file {}: {}", path, e))?;
+    let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    Ok((since_epoch.as_nanos() as u64, meta.len()))
+}
+
+impl Default for FileIndexBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl FileIndexBuilder {
     pub fn new() -> Self {
         Self {
@@ -225,12 +911,57 @@ impl FileIndexBuilder {
 }
 
 impl FileContent {
+    /// Reads `path` unless `filter` leaves it out, unreadable files are
+    /// warned about and left out too.
+    pub fn read(path: AbsPath, filter: &FileFilter) -> Result<FileContent, SkipReason> {
+        let name = path.path.clone();
+        match FileContent::read_filtered(path, filter) {
+            Ok(Ok(content)) => Ok(content),
+            Ok(Err(reason)) => {
+                debug!("Skip {} file {}", reason, name);
+                Err(reason)
+            }
+            Err(e) => {
+                warn!("Failed to read file: {}", e);
+                Err(SkipReason::Unreadable)
+            }
+        }
+    }
+    fn read_filtered(path: AbsPath, filter: &FileFilter) -> Result<Result<FileContent, SkipReason>> {
+        let size = std::fs::metadata(&path.path)
+            .map_err(|e| anyhow!("Failed to stat file {}: {}", path.path, e))?
+            .len();
+        if filter.oversized(size) {
+            return Ok(Err(SkipReason::Oversized));
+        }
+        let content = std::fs::read(&path.path)
+            .map_err(|e| anyhow!("Failed to read file {}: {}", path.path, e))?;
+        match filter.classify(&content) {
+            Some(reason) => Ok(Err(reason)),
+            None => Ok(Ok(FileContent::try_from((path, content))?)),
+        }
+    }
     pub fn get_full_file_name(&self) -> AbsPath {
         self.full_file_name.clone()
     }
-    pub fn get_lines(&self) -> &Vec<String> {
+    pub fn get_lines(&self) -> &Vec<Vec<u8>> {
         &self.lines
     }
+    pub fn encoding(&self) -> TextEncoding {
+        self.encoding
+    }
+    pub fn crlf_lines(&self) -> &[u32] {
+        &self.crlf_lines
+    }
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+    pub fn size(&self) -> u64 {
+        self.size
+    }
 }
 
 impl From<String> for AbsPath {
@@ -245,36 +976,304 @@ impl ToString for AbsPath {
     }
 }
 
-impl TryFrom<FileIndexBuilder> for FileIndexFinalBuilder {
+impl TryFrom<(FileIndexBuilder, &FileFilter)> for FileIndexFinalBuilder {
     type Error = Error;
-    fn try_from(builder: FileIndexBuilder) -> Result<Self, Self::Error> {
+    fn try_from((builder, filter): (FileIndexBuilder, &FileFilter)) -> Result<Self, Self::Error> {
         info!("start reading files.");
-        let files = builder
+        let (files, skipped): (Vec<_>, Vec<_>) = builder
             .file_to_id
             .into_par_iter()
-            .map(|(path, id)| (id, FileContent::try_from(path.clone())))
-            .map(|(id, ret)| ret.map(|content| (id, content)))
-            .filter_map(|result| match result {
-                Ok(file) => Some(file),
-                Err(e) => {
-                    warn!("Failed to read file: {}", e);
-                    None
-                }
-            })
-            .collect::<Vec<(FileIndex, FileContent)>>();
-        Ok(FileIndexFinalBuilder { files })
+            .map(|(path, id)| FileContent::read(path, filter).map(|content| (id, content)))
+            .partition_map(|file| match file {
+                Ok(file) => rayon::iter::Either::Left(file),
+                Err(reason) => rayon::iter::Either::Right(reason),
+            });
+        Ok(FileIndexFinalBuilder {
+            files,
+            skipped: skipped.into_iter().collect(),
+        })
     }
 }
 
-impl TryFrom<AbsPath> for FileContent {
+/// `content` is the whole file, in any encoding.
+impl TryFrom<(AbsPath, Vec<u8>)> for FileContent {
     type Error = Error;
-    fn try_from(path: AbsPath) -> Result<Self, Self::Error> {
-        let content = std::fs::read_to_string(&path.path)
-            .map_err(|e| anyhow!("Failed to read file {}: {}", path.path, e))?;
-        let lines = content.lines().map(String::from).collect();
+    fn try_from((path, content): (AbsPath, Vec<u8>)) -> Result<Self, Self::Error> {
+        let mtime = modified_time(&path.path)?;
+        let hash = xxhash_rust::xxh3::xxh3_64(&content);
+        let size = content.len() as u64;
         Ok(FileContent {
             full_file_name: path,
-            lines,
+            lines: split_lines(&content),
+            encoding: TextEncoding::detect(&content),
+            crlf_lines: crlf_lines(&content),
+            mtime,
+            hash,
+            size,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ngram::path::NgramPath;
+    use crate::test_dir::TempDir;
+
+    fn load_pack(
+        base_path: &Path,
+        pack: &NgramPack,
+    ) -> Result<HashMap<NgramIndex, FilesLinesIndex>> {
+        let shards = (0..pack.shards())
+            .into_par_iter()
+            .map(|shard| {
+                let path = NgramShardPath::from(shard).path(base_path);
+                std::fs::read(&path).map_err(|e| anyhow!("Failed to read {:?}: {}", path, e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        pack.ranges()
+            .par_iter()
+            .map(|(ngram, pack_range)| {
+                let range = pack_range.range();
+                let data = shards
+                    .get(pack_range.shard() as usize)
+                    .and_then(|shard| shard.get(range.start as usize..range.end() as usize))
+                    .ok_or_else(|| anyhow!("ngram {:?} out of pack range", ngram))?;
+                Ok((ngram.clone(), NgramData::from_data(data)?.files_lines()))
+            })
+            .collect()
+    }
+
+    fn ngram_files(base_path: &Path, ngram: &str) -> Vec<u32> {
+        let ngram = NgramIndex::from(ngram.as_bytes());
+        let path = NgramPath::from(&ngram).path(base_path);
+        match std::fs::read(path) {
+            Ok(data) => NgramData::from_data(&data)
+                .unwrap()
+                .files_lines()
+                .files_lines()
+                .iter()
+                .map(|file_lines| file_lines.file_id().file_id())
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    #[test]
+    fn update_keeps_file_ids_and_rewrites_changed_ngrams() {
+        let temp = TempDir::from("update");
+        let dir = temp.path();
+        let src = dir.join("src");
+        let index = dir.join("index");
+        std::fs::create_dir_all(&src).unwrap();
+        let a = src.join("a.c").to_string_lossy().to_string();
+        let b = src.join("b.c").to_string_lossy().to_string();
+        let c = src.join("c.c").to_string_lossy().to_string();
+        std::fs::write(&a, "alpha\n").unwrap();
+        std::fs::write(&b, "beta\n").unwrap();
+
+        let mut file_builder = FileIndexBuilder::new();
+        file_builder.build(vec![a.clone(), b.clone()]).unwrap();
+        let mut builder = Builder::new(3).unwrap();
+        builder
+            .index(FileIndexFinalBuilder::try_from((file_builder, &FileFilter::default())).unwrap())
+            .unwrap();
+        builder.dump(&index).unwrap();
+        let a_id = ngram_files(&index, "alp");
+        assert_eq!(a_id.len(), 1);
+
+        std::fs::write(&a, "gamma\n").unwrap();
+        std::fs::write(&c, "delta\n").unwrap();
+        // Coarse timestamps may leave the rewrite with the old mtime.
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(&a)
+            .and_then(|file| file.set_modified(later))
+            .unwrap();
+        UpdateBuilder::load(&index)
+            .unwrap()
+            .update(&index, vec![a.clone(), c.clone()])
+            .unwrap();
+
+        assert!(ngram_files(&index, "alp").is_empty());
+        assert!(ngram_files(&index, "bet").is_empty());
+        assert_eq!(ngram_files(&index, "gam"), a_id);
+        assert_eq!(ngram_files(&index, "del"), vec![2]);
+        let global = std::fs::read(GlobalDataPath::from(()).path(&index)).unwrap();
+        let global = GlobalData::from_data(&global).unwrap();
+        assert!(!global.has_index(&NgramIndex::from("alp".as_bytes())));
+        assert!(global.has_index(&NgramIndex::from("gam".as_bytes())));
+        let paths = std::fs::read(PathTablePath::from(()).path(&index)).unwrap();
+        let paths = PathTable::from_data(&paths).unwrap();
+        assert_eq!(paths.path(&FileIndex::from(2)), Some(c.clone()));
+    }
+
+    #[test]
+    fn update_reads_files_with_same_mtime_and_new_length() {
+        let temp = TempDir::from("update-len");
+        let dir = temp.path();
+        let src = dir.join("src");
+        let index = dir.join("index");
+        std::fs::create_dir_all(&src).unwrap();
+        let a = src.join("a.c").to_string_lossy().to_string();
+        std::fs::write(&a, "alpha\n").unwrap();
+        let mut file_builder = FileIndexBuilder::new();
+        file_builder.build(vec![a.clone()]).unwrap();
+        let mut builder = Builder::new(3).unwrap();
+        let files = FileIndexFinalBuilder::try_from((file_builder, &FileFilter::default()));
+        builder.index(files.unwrap()).unwrap();
+        builder.dump(&index).unwrap();
+
+        let mtime = std::fs::metadata(&a)
+            .and_then(|meta| meta.modified())
+            .unwrap();
+        std::fs::write(&a, "gamma ray\n").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&a)
+            .and_then(|file| file.set_modified(mtime))
+            .unwrap();
+        UpdateBuilder::load(&index)
+            .unwrap()
+            .update(&index, vec![a.clone()])
+            .unwrap();
+
+        assert!(ngram_files(&index, "alp").is_empty());
+        assert_eq!(ngram_files(&index, "gam"), vec![0]);
+    }
+
+    #[test]
+    fn packed_update_keeps_unchanged_ranges() {
+        let temp = TempDir::from("pack-update");
+        let dir = temp.path();
+        let src = dir.join("src");
+        let index = dir.join("index");
+        std::fs::create_dir_all(&src).unwrap();
+        let a = src.join("a.c").to_string_lossy().to_string();
+        let b = src.join("b.c").to_string_lossy().to_string();
+        std::fs::write(&a, "alpha shared\n").unwrap();
+        std::fs::write(&b, "beta shared\n").unwrap();
+        let mut file_builder = FileIndexBuilder::new();
+        file_builder.build(vec![a.clone(), b.clone()]).unwrap();
+        let mut builder = Builder::new(3).unwrap().with_pack_shards(Some(2)).unwrap();
+        let files = FileIndexFinalBuilder::try_from((file_builder, &FileFilter::default()));
+        builder.index(files.unwrap()).unwrap();
+        builder.dump(&index).unwrap();
+        let global = || {
+            let data = std::fs::read(GlobalDataPath::from(()).path(&index)).unwrap();
+            GlobalData::from_data(&data).unwrap()
+        };
+        let range = |global: &GlobalData, ngram: &str| {
+            global.pack_range(&NgramIndex::from(ngram.as_bytes()))
+        };
+        let before = global();
+
+        std::fs::write(&a, "gamma shared\n").unwrap();
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(&a)
+            .and_then(|file| file.set_modified(later))
+            .unwrap();
+        UpdateBuilder::load(&index)
+            .unwrap()
+            .update(&index, vec![a.clone(), b.clone()])
+            .unwrap();
+
+        let after = global();
+        // Only in b.c, or in both files at the same lines.
+        assert_eq!(range(&after, "bet"), range(&before, "bet"));
+        assert_eq!(range(&after, "sha"), range(&before, "sha"));
+        assert_eq!(range(&after, "alp"), None);
+        assert!(range(&after, "gam").is_some());
+        let packed = load_pack(&index, after.pack().unwrap()).unwrap();
+        let files = |ngram: &str| packed[&NgramIndex::from(ngram.as_bytes())].files_lines().len();
+        assert_eq!((files("gam"), files("sha"), files("bet")), (1, 2, 1));
+    }
+
+    #[test]
+    fn streaming_build_matches_in_memory_build() {
+        let temp = TempDir::from("stream");
+        let dir = temp.path();
+        let src = dir.join("src");
+        std::fs::create_dir_all(&src).unwrap();
+        let files = [
+            "alpha beta\ngamma\n",
+            "beta\nalphabet\n",
+            "gamma delta\nbeta\n",
+            "alpha\0beta\n",
+            "// @generated\nalpha gamma\n",
+        ];
+        let files = files
+            .iter()
+            .enumerate()
+            .map(|(i, content)| {
+                let path = src.join(format!("{}.c", i)).to_string_lossy().to_string();
+                std::fs::write(&path, content).unwrap();
+                path
+            })
+            .collect::<Vec<_>>();
+        let file_builder = || {
+            let mut file_builder = FileIndexBuilder::new();
+            file_builder.build(files.clone()).unwrap();
+            file_builder
+        };
+        let mut builder = Builder::new(3).unwrap();
+        builder
+            .index(FileIndexFinalBuilder::try_from((file_builder(), &FileFilter::default())).unwrap())
+            .unwrap();
+        let expected = &builder.ngram_to_files_lines;
+        let skipped = "skipped 2 files: 1 binary, 1 generated";
+        assert_eq!(builder.skipped().to_string(), skipped);
+
+        // A one byte limit spills a run after every file.
+        let index = dir.join("index");
+        builder.index_streaming(file_builder(), &index, 1).unwrap();
+        assert!(!index.join("runs").exists());
+        let global = std::fs::read(GlobalDataPath::from(()).path(&index)).unwrap();
+        let global = GlobalData::from_data(&global).unwrap();
+        assert_eq!(global.header().file_count(), 3);
+        assert_eq!(global.skipped().to_string(), skipped);
+        expected.iter().for_each(|(ngram, files_lines)| {
+            let data = std::fs::read(NgramPath::from(ngram).path(&index)).unwrap();
+            assert_eq!(&NgramData::from_data(&data).unwrap().files_lines(), files_lines);
+            let files = files_lines.files_lines().len() as u32;
+            assert_eq!(global.document_frequency(ngram), Some(files));
+        });
+        let paths = std::fs::read(PathTablePath::from(()).path(&index)).unwrap();
+        let paths = PathTable::from_data(&paths).unwrap();
+        assert_eq!(paths.path(&FileIndex::from(2)), Some(files[2].clone()));
+
+        let packed = dir.join("packed");
+        let builder = Builder::new(3).unwrap().with_pack_shards(Some(3)).unwrap();
+        builder.index_streaming(file_builder(), &packed, 1).unwrap();
+        let global = std::fs::read(GlobalDataPath::from(()).path(&packed)).unwrap();
+        let global = GlobalData::from_data(&global).unwrap();
+        assert_eq!(&load_pack(&packed, global.pack().unwrap()).unwrap(), expected);
+        assert_eq!(std::fs::read_dir(packed.join("packs")).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn pack_round_trip() {
+        let temp = TempDir::from("pack");
+        let dir = temp.path();
+        let ngram_to_files_lines = ["abc", "bcd", "cde"]
+            .iter()
+            .enumerate()
+            .map(|(id, ngram)| {
+                let lines = LinesIndex::from(vec![LineIndex::from(id as u32)]);
+                let file_lines = FileLinesIndex::from((FileIndex::from(id as u32), lines));
+                (
+                    NgramIndex::from(ngram.as_bytes()),
+                    FilesLinesIndex::from(vec![file_lines]),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        let pack = dump_pack(dir, &ngram_to_files_lines, 2).unwrap();
+        assert_eq!(pack.shards(), 2);
+        assert_eq!(pack.ranges().len(), 3);
+        let loaded = load_pack(dir, &pack).unwrap();
+        assert_eq!(loaded, ngram_to_files_lines);
+    }
+}
@@ -1,14 +1,82 @@
 use crate::ngram::builder::FileContent;
-use crate::ngram::index::{FilesLinesIndex, LineIndex, NgramIndex};
-use anyhow::Result;
+use crate::ngram::encoding::TextEncoding;
+use crate::ngram::filter::{FileFilter, SkipSummary};
+use crate::ngram::index::{FileIndex, FilesLinesIndex, LineIndex, NgramIndex, NgramShape};
+use crate::ngram::posting::{Posting, PostingIter};
+use crate::range::Range;
+use anyhow::{Result, anyhow};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 use wasm_bindgen::prelude::*;
 
+/// First bytes of `global.data`, identify an igrep ngram index.
+pub const INDEX_MAGIC: [u8; 4] = *b"IGRP";
+/// Version of the on disk layout of `global.data`, `paths.data`, ngram and file blobs.
+///
+/// Bump it whenever any serialized struct of the ngram index changes. Indexes
+/// of another version are refused with a "please rebuild" error, the upgrade
+/// path is to run `igrep index-new` again, `index-update` can not migrate them.
+pub const INDEX_FORMAT_VERSION: u32 = 12;
+/// A full path is stored every this many entries of `PathTable`, so a lookup
+/// decodes at most this many entries.
+const PATH_TABLE_BLOCK: usize = 16;
+
+/// Self describing header, always the first field of `GlobalData`. Its
+/// layout must never change so that any igrep can report who built an index.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IndexHeader {
+    magic: [u8; 4],
+    format_version: u32,
+    ngram_len: u8,
+    tool_version: String,
+    created: u64,
+    file_count: u32,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct GlobalData {
-    ngram_len: u8,
-    indexs: HashSet<NgramIndex>,
+    header: IndexHeader,
+    /// Ngrams with their document frequency, the number of files in their posting list.
+    indexs: HashMap<NgramIndex, u32>,
+    pack: Option<NgramPack>,
+    /// Sorted `[start, end)` runs of the file ids in the index, used by full scans.
+    file_runs: Vec<(u32, u32)>,
+    /// Lowercased lines are indexed too, as folded ngrams.
+    case_fold: bool,
+    /// Sparse ngrams of up to `ngram_len` bytes instead of fixed ones.
+    sparse: bool,
+    /// Limits the files were indexed with.
+    filter: FileFilter,
+    /// Files of the file list left out of the index.
+    skipped: SkipSummary,
+}
+
+/// Packed layout: posting lists concatenated into `shards` files, `indexs`
+/// of `GlobalData` is empty and every ngram is located by its range.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NgramPack {
+    shards: u32,
+    ranges: HashMap<NgramIndex, NgramPackRange>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NgramPackRange {
+    shard: u32,
+    range: Range,
+    /// Document frequency of the ngram, see `GlobalData::document_frequency`.
+    files: u32,
+}
+
+/// Full file name of every file, written to `paths.data` apart from
+/// `global.data` so results can be listed and filtered by path without
+/// fetching any file blob. Paths are front coded, each entry keeps the
+/// number of bytes shared with the previous path and the rest of it.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PathTable {
+    /// Sorted file ids, `entries[i]` is the path of `file_ids[i]`.
+    file_ids: Vec<u32>,
+    entries: Vec<(u32, String)>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -16,41 +84,275 @@ pub struct FileData {
     // file_path: String,
     // file_name: String,
     full_file_name: String,
-    lines_paths: Vec<String>,
+    /// Raw bytes of every line, in `encoding`, without their line end.
+    lines: Vec<Vec<u8>>,
+    encoding: TextEncoding,
+    /// Lines ending in `\r\n` instead of `\n`, sorted.
+    crlf_lines: Vec<u32>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Posting list of one ngram, kept encoded until it is walked.
+#[derive(Debug)]
 pub struct NgramData {
-    files_lines: FilesLinesIndex,
+    posting: Posting,
+}
+
+/// Per file state used by `index-update` to detect changed files.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FileMeta {
+    full_file_name: String,
+    file_index: FileIndex,
+    mtime: u64,
+    hash: u64,
+    len: u64,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct FilesMetaData {
+    files: Vec<FileMeta>,
 }
 
 impl FileData {
     pub fn full_file_name(&self) -> &str {
         &self.full_file_name
     }
-    pub fn lines(&self, line_index: &LineIndex) -> Option<&String> {
-        self.lines_paths.get(line_index.line_id() as usize)
+    pub fn lines(&self, line_index: &LineIndex) -> Option<&[u8]> {
+        self.lines
+            .get(line_index.line_id() as usize)
+            .map(Vec::as_slice)
+    }
+    pub fn all_lines(&self) -> &[Vec<u8>] {
+        &self.lines
+    }
+    pub fn encoding(&self) -> TextEncoding {
+        self.encoding
+    }
+    /// Line `line` decoded for display, with its byte ranges `ranges` moved
+    /// into the decoded line.
+    pub fn display_line(&self, line: u32, ranges: &[(u32, u32)]) -> (String, Vec<(u32, u32)>) {
+        let bytes = self.lines.get(line as usize).map_or(&[][..], Vec::as_slice);
+        self.encoding.decode_ranges(bytes, ranges)
+    }
+    /// Bytes of line `line` when its decoded text differs from them.
+    pub fn raw_line(&self, line: u32) -> Option<Vec<u8>> {
+        let bytes = self.lines.get(line as usize)?;
+        match (self.encoding, std::str::from_utf8(bytes)) {
+            (TextEncoding::Utf8, Ok(_)) => None,
+            _ => Some(bytes.clone()),
+        }
+    }
+    /// `\r\n` or `\n`, the line end of line `line` in the file.
+    pub fn line_end(&self, line: u32) -> &'static str {
+        match self.crlf_lines.binary_search(&line) {
+            Ok(_) => "\r\n",
+            Err(_) => "\n",
+        }
     }
 }
 
-impl GlobalData {
+impl IndexHeader {
     pub fn ngram_len(&self) -> u8 {
         self.ngram_len
     }
+    pub fn format_version(&self) -> u32 {
+        self.format_version
+    }
+    pub fn tool_version(&self) -> &str {
+        &self.tool_version
+    }
+    pub fn created(&self) -> u64 {
+        self.created
+    }
+    pub fn file_count(&self) -> u32 {
+        self.file_count
+    }
+    fn check(&self) -> Result<()> {
+        if self.format_version != INDEX_FORMAT_VERSION {
+            return Err(anyhow!(
+                "index built by igrep {} (format {}), igrep {} reads format {}, please rebuild it with index-new",
+                self.tool_version,
+                self.format_version,
+                env!("CARGO_PKG_VERSION"),
+                INDEX_FORMAT_VERSION
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl GlobalData {
+    pub fn header(&self) -> &IndexHeader {
+        &self.header
+    }
+    pub fn ngram_len(&self) -> u8 {
+        self.header.ngram_len()
+    }
+    pub fn case_fold(&self) -> bool {
+        self.case_fold
+    }
+    pub fn set_case_fold(&mut self, case_fold: bool) {
+        self.case_fold = case_fold;
+    }
+    pub fn set_sparse(&mut self, sparse: bool) {
+        self.sparse = sparse;
+    }
+    pub fn filter(&self) -> &FileFilter {
+        &self.filter
+    }
+    pub fn set_filter(&mut self, filter: FileFilter) {
+        self.filter = filter;
+    }
+    pub fn skipped(&self) -> &SkipSummary {
+        &self.skipped
+    }
+    pub fn set_skipped(&mut self, skipped: SkipSummary) {
+        self.skipped = skipped;
+    }
+    pub fn ngram_shape(&self) -> NgramShape {
+        match self.sparse {
+            true => NgramShape::Sparse(self.ngram_len()),
+            false => NgramShape::Fixed(self.ngram_len()),
+        }
+    }
+    pub fn files(&self) -> impl Iterator<Item = FileIndex> + '_ {
+        self.file_runs
+            .iter()
+            .flat_map(|(start, end)| (*start..*end).map(FileIndex::from))
+    }
+    pub fn set_files(&mut self, files: impl IntoIterator<Item = FileIndex>) {
+        let mut files = files
+            .into_iter()
+            .map(|file_index| file_index.file_id())
+            .collect::<Vec<_>>();
+        files.sort();
+        files.dedup();
+        self.header.file_count = files.len() as u32;
+        self.file_runs = files.iter().fold(Vec::new(), |mut runs, id| {
+            match runs.last_mut() {
+                Some((_, end)) if end == id => *end += 1,
+                _ => runs.push((*id, id + 1)),
+            }
+            runs
+        });
+    }
     pub fn has_index(&self, index: &NgramIndex) -> bool {
-        self.indexs.contains(index)
+        match &self.pack {
+            Some(pack) => pack.ranges.contains_key(index),
+            None => self.indexs.contains_key(index),
+        }
+    }
+    /// Number of files holding `index`, `None` when it is not in the index.
+    pub fn document_frequency(&self, index: &NgramIndex) -> Option<u32> {
+        match &self.pack {
+            Some(pack) => pack.ranges.get(index).map(|range| range.files),
+            None => self.indexs.get(index).copied(),
+        }
+    }
+    pub fn pack(&self) -> Option<&NgramPack> {
+        self.pack.as_ref()
+    }
+    pub fn pack_range(&self, index: &NgramIndex) -> Option<NgramPackRange> {
+        self.pack
+            .as_ref()
+            .and_then(|pack| pack.ranges.get(index).copied())
+    }
+    pub fn set_pack(&mut self, pack: NgramPack) {
+        self.indexs.clear();
+        self.pack = Some(pack);
+    }
+    pub fn insert_index(&mut self, index: NgramIndex, files: u32) {
+        self.indexs.insert(index, files);
+    }
+    pub fn remove_index(&mut self, index: &NgramIndex) {
+        self.indexs.remove(index);
+    }
+}
+
+impl PathTable {
+    /// Full file name of `file_index`, `None` when it is not in the index.
+    pub fn path(&self, file_index: &FileIndex) -> Option<String> {
+        let index = self.file_ids.binary_search(&file_index.file_id()).ok()?;
+        let start = index - index % PATH_TABLE_BLOCK;
+        let path = self.entries[start + 1..=index].iter().fold(
+            self.entries[start].1.clone(),
+            |mut path, (shared, rest)| {
+                path.truncate(*shared as usize);
+                path.push_str(rest);
+                path
+            },
+        );
+        Some(path)
+    }
+}
+
+impl NgramPack {
+    pub fn shards(&self) -> u32 {
+        self.shards
+    }
+    pub fn ranges(&self) -> &HashMap<NgramIndex, NgramPackRange> {
+        &self.ranges
+    }
+}
+
+impl NgramPackRange {
+    pub fn shard(&self) -> u32 {
+        self.shard
+    }
+    pub fn range(&self) -> &Range {
+        &self.range
+    }
+}
+
+impl FileMeta {
+    pub fn full_file_name(&self) -> &str {
+        &self.full_file_name
+    }
+    pub fn file_index(&self) -> FileIndex {
+        self.file_index
+    }
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+    /// Same content as `other`, ignoring the modification time.
+    pub fn same_content(&self, other: &FileMeta) -> bool {
+        self.hash == other.hash && self.len == other.len
+    }
+}
+
+impl FilesMetaData {
+    pub fn files(&self) -> &[FileMeta] {
+        &self.files
+    }
+    pub fn max_file_index(&self) -> Option<FileIndex> {
+        self.files.iter().map(|meta| meta.file_index).max()
     }
 }
 
 impl NgramData {
-    pub fn files_lines(&self) -> &FilesLinesIndex {
-        &self.files_lines
+    pub fn files_lines(&self) -> FilesLinesIndex {
+        FilesLinesIndex::from(&self.posting)
+    }
+    pub fn iter(&self) -> PostingIter<'_> {
+        self.posting.iter()
+    }
+    pub fn to_data(&self) -> Result<Vec<u8>> {
+        self.posting.to_bytes()
+    }
+    pub fn from_data(data: &[u8]) -> Result<Self> {
+        Ok(NgramData {
+            posting: Posting::from_bytes(data)?,
+        })
     }
 }
 
 impl From<FilesLinesIndex> for NgramData {
     fn from(value: FilesLinesIndex) -> Self {
-        NgramData { files_lines: value }
+        NgramData {
+            posting: Posting::from(&value),
+        }
     }
 }
 
@@ -58,23 +360,140 @@ impl From<&FileContent> for FileData {
     fn from(value: &FileContent) -> Self {
         FileData {
             full_file_name: value.get_full_file_name().to_string(),
-            lines_paths: value.get_lines().clone(),
+            lines: value.get_lines().clone(),
+            encoding: value.encoding(),
+            crlf_lines: value.crlf_lines().to_vec(),
+        }
+    }
+}
+
+impl From<(FileIndex, &FileContent)> for FileMeta {
+    fn from((file_index, value): (FileIndex, &FileContent)) -> Self {
+        FileMeta {
+            full_file_name: value.get_full_file_name().to_string(),
+            file_index,
+            mtime: value.mtime(),
+            hash: value.hash(),
+            len: value.size(),
+        }
+    }
+}
+
+impl From<Vec<FileMeta>> for FilesMetaData {
+    fn from(value: Vec<FileMeta>) -> Self {
+        let mut files = value;
+        files.sort_by_key(|meta| meta.file_index);
+        FilesMetaData { files }
+    }
+}
+
+impl From<(u8, u32)> for IndexHeader {
+    fn from((ngram_len, file_count): (u8, u32)) -> Self {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        IndexHeader {
+            magic: INDEX_MAGIC,
+            format_version: INDEX_FORMAT_VERSION,
+            ngram_len,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            created,
+            file_count,
         }
     }
 }
 
-impl From<(u8, HashSet<NgramIndex>)> for GlobalData {
-    fn from((value, indexs): (u8, HashSet<NgramIndex>)) -> Self {
+impl From<(IndexHeader, HashMap<NgramIndex, u32>)> for GlobalData {
+    fn from((header, indexs): (IndexHeader, HashMap<NgramIndex, u32>)) -> Self {
         GlobalData {
-            ngram_len: value,
-            indexs: indexs,
+            header,
+            indexs,
+            pack: None,
+            file_runs: vec![],
+            case_fold: false,
+            sparse: false,
+            filter: FileFilter::default(),
+            skipped: SkipSummary::default(),
         }
     }
 }
 
-impl FromToData<'_> for GlobalData {}
-impl FromToData<'_> for NgramData {}
+impl From<(IndexHeader, NgramPack)> for GlobalData {
+    fn from((header, pack): (IndexHeader, NgramPack)) -> Self {
+        GlobalData {
+            header,
+            indexs: HashMap::new(),
+            pack: Some(pack),
+            file_runs: vec![],
+            case_fold: false,
+            sparse: false,
+            filter: FileFilter::default(),
+            skipped: SkipSummary::default(),
+        }
+    }
+}
+
+impl From<Vec<(FileIndex, String)>> for PathTable {
+    fn from(mut files: Vec<(FileIndex, String)>) -> Self {
+        files.sort_by_key(|(file_index, _)| file_index.file_id());
+        files.dedup_by_key(|(file_index, _)| file_index.file_id());
+        let file_ids = files.iter().map(|(file_index, _)| file_index.file_id()).collect();
+        let entries = files
+            .iter()
+            .enumerate()
+            .map(|(i, (_, path))| {
+                let shared = match i % PATH_TABLE_BLOCK {
+                    0 => 0,
+                    _ => path
+                        .chars()
+                        .zip(files[i - 1].1.chars())
+                        .take_while(|(a, b)| a == b)
+                        .map(|(a, _)| a.len_utf8())
+                        .sum::<usize>(),
+                };
+                (shared as u32, path[shared..].to_string())
+            })
+            .collect();
+        PathTable { file_ids, entries }
+    }
+}
+
+impl From<(u32, HashMap<NgramIndex, NgramPackRange>)> for NgramPack {
+    fn from((shards, ranges): (u32, HashMap<NgramIndex, NgramPackRange>)) -> Self {
+        NgramPack { shards, ranges }
+    }
+}
+
+/// `(shard, range, files)`
+impl From<(u32, Range, u32)> for NgramPackRange {
+    fn from((shard, range, files): (u32, Range, u32)) -> Self {
+        NgramPackRange {
+            shard,
+            range,
+            files,
+        }
+    }
+}
+
+impl FromToData<'_> for GlobalData {
+    /// Check the header before decoding the rest, so an index of another
+    /// format fails with a readable error instead of a postcard one.
+    fn from_data(data: &[u8]) -> Result<Self> {
+        if !data.starts_with(&INDEX_MAGIC) {
+            return Err(anyhow!(
+                "not an igrep index or built by an igrep without index header, please rebuild it with index-new"
+            ));
+        }
+        let (header, _) = postcard::take_from_bytes::<IndexHeader>(data)
+            .map_err(|e| anyhow!("index header is broken: {}", e))?;
+        header.check()?;
+        let ans = postcard::from_bytes(data)?;
+        Ok(ans)
+    }
+}
 impl FromToData<'_> for FileData {}
+impl FromToData<'_> for PathTable {}
+impl FromToData<'_> for FilesMetaData {}
 
 pub trait FromToData<'a> {
     fn to_data(&self) -> Result<Vec<u8>>
@@ -92,3 +511,62 @@ pub trait FromToData<'a> {
         Ok(ans)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn global_data_round_trip() {
+        let global = GlobalData::from((IndexHeader::from((3, 2)), HashMap::new()));
+        let data = global.to_data().unwrap();
+        assert!(data.starts_with(&INDEX_MAGIC));
+        let global = GlobalData::from_data(&data).unwrap();
+        assert_eq!(global.ngram_len(), 3);
+        assert_eq!(global.header().file_count(), 2);
+    }
+
+    #[test]
+    fn global_data_file_runs() {
+        let mut global = GlobalData::from((IndexHeader::from((3, 0)), HashMap::new()));
+        global.set_files([5, 0, 1, 2, 7, 6].map(FileIndex::from));
+        assert_eq!(global.file_runs, vec![(0, 3), (5, 8)]);
+        assert_eq!(global.header().file_count(), 6);
+        let files = global.files().map(|f| f.file_id()).collect::<Vec<_>>();
+        assert_eq!(files, vec![0, 1, 2, 5, 6, 7]);
+    }
+
+    #[test]
+    fn path_table_front_codes_paths() {
+        let paths = (0..40)
+            .rev()
+            .map(|id| (FileIndex::from(id * 2), format!("/src/dir{}/é{}.c", id / 7, id)))
+            .collect::<Vec<_>>();
+        let table = PathTable::from(paths);
+        assert_eq!(table.file_ids.len(), 40);
+        assert_eq!(table.entries[1], (12, "1.c".to_string()));
+        assert_eq!(table.entries[PATH_TABLE_BLOCK].0, 0);
+        let table = PathTable::from_data(&table.to_data().unwrap()).unwrap();
+        (0..40).for_each(|id| {
+            let path = table.path(&FileIndex::from(id * 2));
+            assert_eq!(path, Some(format!("/src/dir{}/é{}.c", id / 7, id)));
+        });
+        assert_eq!(table.path(&FileIndex::from(3)), None);
+        assert_eq!(table.path(&FileIndex::from(80)), None);
+    }
+
+    #[test]
+    fn global_data_rejects_other_format() {
+        let mut header = IndexHeader::from((3, 0));
+        header.format_version = INDEX_FORMAT_VERSION + 1;
+        header.tool_version = "9.9.9".to_string();
+        let data = GlobalData::from((header, HashMap::new())).to_data().unwrap();
+        let err = GlobalData::from_data(&data).err().unwrap().to_string();
+        assert!(err.contains("igrep 9.9.9"), "{}", err);
+        assert!(err.contains("please rebuild"), "{}", err);
+
+        let old = postcard::to_stdvec(&(3u8, std::collections::HashSet::<NgramIndex>::new())).unwrap();
+        let err = GlobalData::from_data(&old).err().unwrap().to_string();
+        assert!(err.contains("please rebuild"), "{}", err);
+    }
+}
@@ -0,0 +1,135 @@
+use encoding_rs::{SHIFT_JIS, WINDOWS_1252};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// Encoding of a file, files are indexed and searched as raw bytes and only
+/// decoded to show their lines.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TextEncoding {
+    #[default]
+    Utf8,
+    ShiftJis,
+    /// Latin-1, decoded as its windows-1252 superset.
+    Latin1,
+}
+
+impl TextEncoding {
+    /// UTF-8 when valid, else Shift-JIS when it decodes without error, else
+    /// Latin-1, which takes any bytes. Latin-1 text rarely passes as
+    /// Shift-JIS: an accented letter is a lead byte and is mostly followed by
+    /// ASCII, which is no valid trail byte.
+    pub fn detect(content: &[u8]) -> Self {
+        if std::str::from_utf8(content).is_ok() {
+            TextEncoding::Utf8
+        } else if SHIFT_JIS
+            .decode_without_bom_handling_and_without_replacement(content)
+            .is_some()
+        {
+            TextEncoding::ShiftJis
+        } else {
+            TextEncoding::Latin1
+        }
+    }
+
+    /// Lossy, an invalid sequence becomes U+FFFD.
+    pub fn decode<'a>(&self, bytes: &'a [u8]) -> Cow<'a, str> {
+        match self {
+            TextEncoding::Utf8 => String::from_utf8_lossy(bytes),
+            TextEncoding::ShiftJis => SHIFT_JIS.decode_without_bom_handling(bytes).0,
+            TextEncoding::Latin1 => WINDOWS_1252.decode_without_bom_handling(bytes).0,
+        }
+    }
+
+    /// `line` decoded, with the byte ranges `ranges` of `line` moved to the
+    /// same text in the decoded line. A range cutting a character is widened
+    /// to the whole character, an empty one inside a character is dropped.
+    pub fn decode_ranges(&self, line: &[u8], ranges: &[(u32, u32)]) -> (String, Vec<(u32, u32)>) {
+        let content = self.decode(line).into_owned();
+        let valid_utf8 = matches!(self, TextEncoding::Utf8) && content.len() == line.len();
+        let offset = |end: u32| match valid_utf8 {
+            true => end as usize,
+            false => self.decode(&line[..end as usize]).len().min(content.len()),
+        };
+        let ranges = ranges
+            .iter()
+            .filter_map(|(start, end)| {
+                let (start, end) = (offset(*start), offset(*end));
+                let empty_inside = start == end && !content.is_char_boundary(start);
+                let start = content.floor_char_boundary(start) as u32;
+                let end = content.ceil_char_boundary(end) as u32;
+                (!empty_inside).then_some((start, end))
+            })
+            .collect();
+        (content, ranges)
+    }
+}
+
+/// Lines of `content` like `str::lines`: split at `\n` or `\r\n`, no last
+/// empty line after a final line end.
+pub fn split_lines(content: &[u8]) -> Vec<Vec<u8>> {
+    content
+        .split_inclusive(|byte| *byte == b'\n')
+        .map(|line| match line.strip_suffix(b"\n") {
+            Some(line) => line.strip_suffix(b"\r").unwrap_or(line),
+            None => line,
+        })
+        .map(<[u8]>::to_vec)
+        .collect()
+}
+
+/// Ids of the lines of `split_lines` that ended in `\r\n`.
+pub fn crlf_lines(content: &[u8]) -> Vec<u32> {
+    content
+        .split_inclusive(|byte| *byte == b'\n')
+        .enumerate()
+        .filter(|(_, line)| line.ends_with(b"\r\n"))
+        .map(|(id, _)| id as u32)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_and_decode_legacy_encodings() {
+        let latin1 = b"/* caf\xe9 cr\xe8me */ int m;";
+        let sjis = b"char *s = \"\x93\xfa\x96\x7b\"; // m";
+        assert_eq!(
+            TextEncoding::detect("int café;".as_bytes()),
+            TextEncoding::Utf8
+        );
+        assert_eq!(TextEncoding::detect(latin1), TextEncoding::Latin1);
+        assert_eq!(TextEncoding::detect(sjis), TextEncoding::ShiftJis);
+        assert_eq!(
+            TextEncoding::Latin1.decode(latin1),
+            "/* café crème */ int m;"
+        );
+        assert_eq!(
+            TextEncoding::ShiftJis.decode(sjis),
+            "char *s = \"日本\"; // m"
+        );
+
+        let (content, ranges) = TextEncoding::Latin1.decode_ranges(latin1, &[(17, 20), (3, 7)]);
+        let text = |(start, end): (u32, u32)| &content[start as usize..end as usize];
+        assert_eq!(text(ranges[0]), "int");
+        assert_eq!(text(ranges[1]), "café");
+        let (content, ranges) = TextEncoding::ShiftJis.decode_ranges(sjis, &[(21, 22)]);
+        assert_eq!(&content[ranges[0].0 as usize..ranges[0].1 as usize], "m");
+
+        // Empty matches of a bytes regex may fall inside a character.
+        let cafe = "café".as_bytes();
+        let ranges = [(0, 0), (4, 4), (5, 5), (3, 4)];
+        let (content, ranges) = TextEncoding::Utf8.decode_ranges(cafe, &ranges);
+        assert_eq!(ranges, vec![(0, 0), (5, 5), (3, 5)]);
+        assert_eq!(&content[3..5], "é");
+
+        assert_eq!(
+            split_lines(b"a\r\n\nb\n"),
+            vec![b"a".to_vec(), vec![], b"b".to_vec()]
+        );
+        assert_eq!(split_lines(b"\n\r"), vec![vec![], b"\r".to_vec()]);
+        assert!(split_lines(b"").is_empty());
+        assert_eq!(crlf_lines(b"a\r\n\nb\r\n\r"), vec![0, 2]);
+    }
+}
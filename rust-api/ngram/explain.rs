@@ -0,0 +1,132 @@
+use std::fmt;
+
+/// What one node of an ngram tree does, see `SearchOneEngine::explain`.
+#[derive(Debug)]
+pub enum PlanStep {
+    /// No ngram narrows this part of the pattern, every file is a candidate.
+    All,
+    /// `bytes` is the size of the posting list, `None` when the index lacks the ngram.
+    Gram {
+        ngram: String,
+        folded: bool,
+        bytes: Option<usize>,
+    },
+    /// Intersection of the children.
+    Concat,
+    /// Union of the children.
+    Alternation,
+}
+
+/// A node with the candidates left after it, `None` for every file.
+#[derive(Debug)]
+pub struct PlanNode {
+    step: PlanStep,
+    candidates: Option<Candidates>,
+    children: Vec<PlanNode>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candidates {
+    files: usize,
+    lines: usize,
+}
+
+/// The ngram tree of every query term, the candidates of the whole query and
+/// the file blobs a search has to fetch.
+#[derive(Debug)]
+pub struct QueryPlan {
+    terms: Vec<(String, PlanNode)>,
+    candidates: Option<Candidates>,
+    fetch_files: usize,
+    total_files: usize,
+}
+
+impl PlanNode {
+    fn fmt_indent(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        write!(f, "{:width$}", "", width = depth * 2)?;
+        match &self.step {
+            PlanStep::All => write!(f, "all")?,
+            PlanStep::Gram { ngram, folded, bytes } => {
+                write!(f, "gram {:?}", ngram)?;
+                if *folded {
+                    write!(f, " folded")?;
+                }
+                match bytes {
+                    Some(bytes) => write!(f, " posting {} bytes", bytes)?,
+                    None => write!(f, " not indexed")?,
+                }
+            }
+            PlanStep::Concat => write!(f, "concat")?,
+            PlanStep::Alternation => write!(f, "alternation")?,
+        }
+        writeln!(f, ": {}", CandidatesDisplay(self.candidates))?;
+        self.children
+            .iter()
+            .try_for_each(|child| child.fmt_indent(f, depth + 1))
+    }
+}
+
+struct CandidatesDisplay(Option<Candidates>);
+
+impl fmt::Display for CandidatesDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(candidates) => write!(
+                f,
+                "{} files, {} lines",
+                candidates.files, candidates.lines
+            ),
+            None => write!(f, "all files"),
+        }
+    }
+}
+
+impl fmt::Display for QueryPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.terms.iter().enumerate().try_for_each(|(i, (pattern, node))| {
+            writeln!(f, "term {}: {}", i + 1, pattern)?;
+            node.fmt_indent(f, 1)
+        })?;
+        writeln!(f, "query: {}", CandidatesDisplay(self.candidates))?;
+        write!(
+            f,
+            "fetch {} of {} file blobs",
+            self.fetch_files, self.total_files
+        )
+    }
+}
+
+impl From<(PlanStep, Option<Candidates>, Vec<PlanNode>)> for PlanNode {
+    fn from((step, candidates, children): (PlanStep, Option<Candidates>, Vec<PlanNode>)) -> Self {
+        PlanNode {
+            step,
+            candidates,
+            children,
+        }
+    }
+}
+
+impl From<(usize, usize)> for Candidates {
+    fn from((files, lines): (usize, usize)) -> Self {
+        Candidates { files, lines }
+    }
+}
+
+/// `(terms, candidates, fetch_files, total_files)`
+impl From<(Vec<(String, PlanNode)>, Option<Candidates>, usize, usize)> for QueryPlan {
+    fn from(
+        (terms, candidates, fetch_files, total_files): (
+            Vec<(String, PlanNode)>,
+            Option<Candidates>,
+            usize,
+            usize,
+        ),
+    ) -> Self {
+        QueryPlan {
+            terms,
+            candidates,
+            fetch_files,
+            total_files,
+        }
+    }
+}
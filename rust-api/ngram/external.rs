@@ -0,0 +1,337 @@
+use crate::ngram::index::{
+    FileIndex, FileLinesIndex, FilesLinesIndex, LineIndex, LinesIndex, NgramIndex, NgramShape,
+};
+use anyhow::{Result, anyhow};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Runs merged at once, every open run holds a file and its read buffer.
+pub const MAX_MERGE_RUNS: usize = 64;
+/// Smallest heap block of the allocator, a 3 byte ngram still takes one.
+const MIN_ALLOC: usize = 16;
+
+/// One posting entry, runs are sorted by ngram, then file, then line, the
+/// order of the posting lists.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+pub struct RunEntry {
+    ngram: NgramIndex,
+    file: FileIndex,
+    line: LineIndex,
+}
+
+/// A sorted run spilled to disk by `RunEntry::spill`.
+#[derive(Debug)]
+pub struct Run {
+    path: PathBuf,
+    entries: u64,
+}
+
+struct RunReader {
+    reader: BufReader<File>,
+}
+
+/// Merges sorted runs back into one posting list per ngram, in ngram order.
+pub struct RunMerger {
+    readers: Vec<RunReader>,
+    heap: BinaryHeap<Reverse<(RunEntry, usize)>>,
+    merged: u64,
+}
+
+impl RunEntry {
+    /// Heap block of the ngram bytes, the entry itself lives in its `Vec`.
+    pub fn heap_size(&self) -> usize {
+        self.ngram.ngrams().len().next_multiple_of(MIN_ALLOC)
+    }
+
+    /// Upper bound of the memory of the entries cut from one byte of a
+    /// line, every byte starts at most one fixed ngram and about two sparse
+    /// ones, twice that with folded ngrams.
+    pub fn bytes_per_source_byte(shape: NgramShape, case_fold: bool) -> usize {
+        let ngrams = match shape {
+            NgramShape::Fixed(_) => 1,
+            NgramShape::Sparse(_) => 2,
+        };
+        (std::mem::size_of::<RunEntry>() + MIN_ALLOC) * ngrams * (1 + case_fold as usize)
+    }
+
+    /// Sorts `entries` into the run file `path` and empties them.
+    pub fn spill(path: &Path, entries: &mut Vec<RunEntry>) -> Result<Run> {
+        entries.sort_unstable();
+        let file =
+            File::create(path).map_err(|e| anyhow!("create run {:?} fail. {:?}", path, e))?;
+        let mut writer = BufWriter::new(file);
+        entries
+            .iter()
+            .try_for_each(|entry| entry.write(&mut writer))?;
+        writer.flush()?;
+        let run = Run {
+            path: path.to_path_buf(),
+            entries: entries.len() as u64,
+        };
+        // Give the memory back, the next entries regrow it.
+        *entries = vec![];
+        Ok(run)
+    }
+
+    /// `folded, len, ngram bytes, file id, line id`, integers little endian.
+    fn write(&self, writer: &mut impl Write) -> Result<()> {
+        let ngram = self.ngram.ngrams();
+        writer.write_all(&[self.ngram.is_folded() as u8, ngram.len() as u8])?;
+        writer.write_all(ngram)?;
+        writer.write_all(&self.file.file_id().to_le_bytes())?;
+        writer.write_all(&self.line.line_id().to_le_bytes())?;
+        Ok(())
+    }
+
+    /// `None` at the end of the run.
+    fn read(reader: &mut impl Read) -> Result<Option<RunEntry>> {
+        let mut head = [0u8; 2];
+        match reader.read_exact(&mut head) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        let mut ngram = vec![0u8; head[1] as usize];
+        reader.read_exact(&mut ngram)?;
+        let mut ids = [0u8; 8];
+        reader.read_exact(&mut ids)?;
+        let ngram = NgramIndex::from(ngram.as_slice());
+        Ok(Some(RunEntry {
+            ngram: match head[0] {
+                0 => ngram,
+                _ => ngram.into_folded(),
+            },
+            file: FileIndex::from(u32::from_le_bytes(ids[..4].try_into()?)),
+            line: LineIndex::from(u32::from_le_bytes(ids[4..].try_into()?)),
+        }))
+    }
+}
+
+impl Run {
+    /// Entries spilled into the run.
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    /// Merges `runs` in passes of at most `fan_in` runs into new runs under
+    /// `dir` until no more than `fan_in` are left for `RunMerger::open`.
+    /// Merged runs are removed.
+    pub fn reduce(dir: &Path, mut runs: Vec<Run>, fan_in: usize) -> Result<Vec<Run>> {
+        let fan_in = fan_in.max(2);
+        let mut next_id = runs.len();
+        while runs.len() > fan_in {
+            let mut rest = runs.into_iter().peekable();
+            runs = vec![];
+            while rest.peek().is_some() {
+                let group = rest.by_ref().take(fan_in).collect::<Vec<_>>();
+                if group.len() == 1 {
+                    runs.extend(group);
+                    continue;
+                }
+                runs.push(Run::merge(
+                    &dir.join(format!("merged-{}", next_id)),
+                    &group,
+                )?);
+                next_id += 1;
+                group.iter().try_for_each(Run::remove)?;
+            }
+        }
+        Ok(runs)
+    }
+
+    /// Merges the entries of `runs` into the new run `path`.
+    fn merge(path: &Path, runs: &[Run]) -> Result<Run> {
+        let file =
+            File::create(path).map_err(|e| anyhow!("create run {:?} fail. {:?}", path, e))?;
+        let mut writer = BufWriter::new(file);
+        let mut merger = RunMerger::open(runs)?;
+        while let Some(entry) = merger.pop()? {
+            entry.write(&mut writer)?;
+        }
+        writer.flush()?;
+        Ok(Run {
+            path: path.to_path_buf(),
+            entries: merger.merged(),
+        })
+    }
+
+    fn remove(&self) -> Result<()> {
+        std::fs::remove_file(&self.path)
+            .map_err(|e| anyhow!("remove run {:?} fail. {:?}", self.path, e))
+    }
+}
+
+impl RunReader {
+    fn next(&mut self) -> Result<Option<RunEntry>> {
+        RunEntry::read(&mut self.reader)
+    }
+}
+
+impl RunMerger {
+    pub fn open(runs: &[Run]) -> Result<Self> {
+        let mut readers = runs
+            .iter()
+            .map(|run| {
+                File::open(&run.path)
+                    .map(|file| RunReader {
+                        reader: BufReader::new(file),
+                    })
+                    .map_err(|e| anyhow!("open run {:?} fail. {:?}", run.path, e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let heap = readers
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, reader)| reader.next().transpose().map(|entry| (i, entry)))
+            .map(|(i, entry)| entry.map(|entry| Reverse((entry, i))))
+            .collect::<Result<BinaryHeap<_>>>()?;
+        Ok(RunMerger {
+            readers,
+            heap,
+            merged: 0,
+        })
+    }
+
+    /// Entries merged so far, over all runs.
+    pub fn merged(&self) -> u64 {
+        self.merged
+    }
+
+    fn pop(&mut self) -> Result<Option<RunEntry>> {
+        let Some(Reverse((entry, i))) = self.heap.pop() else {
+            return Ok(None);
+        };
+        if let Some(next) = self.readers[i].next()? {
+            self.heap.push(Reverse((next, i)));
+        }
+        self.merged += 1;
+        Ok(Some(entry))
+    }
+
+    fn peek_ngram(&self) -> Option<&NgramIndex> {
+        self.heap.peek().map(|Reverse((entry, _))| &entry.ngram)
+    }
+
+    /// The next ngram with its posting list.
+    fn next_ngram(&mut self) -> Result<Option<(NgramIndex, FilesLinesIndex)>> {
+        let Some(first) = self.pop()? else {
+            return Ok(None);
+        };
+        let mut files_lines: Vec<(FileIndex, Vec<LineIndex>)> =
+            vec![(first.file, vec![first.line])];
+        while self.peek_ngram() == Some(&first.ngram) {
+            let entry = self.pop()?.expect("peeked entry");
+            match files_lines.last_mut() {
+                Some((file, lines)) if *file == entry.file => lines.push(entry.line),
+                _ => files_lines.push((entry.file, vec![entry.line])),
+            }
+        }
+        let files_lines = files_lines
+            .into_iter()
+            .map(|(file, lines)| FileLinesIndex::from((file, LinesIndex::from(lines))))
+            .collect::<Vec<_>>();
+        Ok(Some((first.ngram, FilesLinesIndex::from(files_lines))))
+    }
+}
+
+impl Iterator for RunMerger {
+    type Item = Result<(NgramIndex, FilesLinesIndex)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_ngram().transpose()
+    }
+}
+
+impl From<(NgramIndex, FileIndex, LineIndex)> for RunEntry {
+    fn from((ngram, file, line): (NgramIndex, FileIndex, LineIndex)) -> Self {
+        RunEntry { ngram, file, line }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TempDir;
+
+    #[test]
+    fn runs_merge_into_posting_lists() {
+        let temp = TempDir::from("runs");
+        let dir = temp.path();
+        let entry = |ngram: &str, file: u32, line: u32| {
+            RunEntry::from((
+                NgramIndex::from(ngram.as_bytes()),
+                FileIndex::from(file),
+                LineIndex::from(line),
+            ))
+        };
+        let mut first = vec![entry("abc", 1, 0), entry("abc", 0, 2), entry("bcd", 0, 2)];
+        let mut second = vec![entry("abc", 0, 5), entry("zzz", 3, 1)];
+        let runs = vec![
+            RunEntry::spill(&dir.join("0"), &mut first).unwrap(),
+            RunEntry::spill(&dir.join("1"), &mut second).unwrap(),
+        ];
+        assert!(first.is_empty());
+        assert_eq!(runs.iter().map(Run::entries).sum::<u64>(), 5);
+
+        let mut merger = RunMerger::open(&runs).unwrap();
+        let (ngram, abc) = merger.next().unwrap().unwrap();
+        assert_eq!(ngram.ngrams(), b"abc");
+        let abc = abc
+            .files_lines()
+            .iter()
+            .map(|file_lines| {
+                let lines = file_lines
+                    .lines_index()
+                    .lines()
+                    .iter()
+                    .map(LineIndex::line_id);
+                (file_lines.file_id().file_id(), lines.collect::<Vec<_>>())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(abc, vec![(0, vec![2, 5]), (1, vec![0])]);
+        assert_eq!(merger.merged(), 3);
+        let rest = merger
+            .map(|next| next.unwrap().0.ngrams().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(rest, vec![b"bcd".to_vec(), b"zzz".to_vec()]);
+    }
+
+    #[test]
+    fn runs_reduce_to_fan_in() {
+        let temp = TempDir::from("reduce");
+        let dir = temp.path();
+        let entry = |ngram: &[u8], file: u32, line: u32| {
+            RunEntry::from((
+                NgramIndex::from(ngram),
+                FileIndex::from(file),
+                LineIndex::from(line),
+            ))
+        };
+        // Every run holds `abc` and a gram of its own, more runs than the fan in.
+        let runs = (0..7u32)
+            .map(|i| {
+                let mut entries = vec![entry(b"abc", i, 0), entry(&[b'a' + i as u8; 3], i, 1)];
+                RunEntry::spill(&dir.join(i.to_string()), &mut entries).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let runs = Run::reduce(dir, runs, 2).unwrap();
+        assert_eq!(runs.len(), 2);
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 2);
+        assert_eq!(runs.iter().map(Run::entries).sum::<u64>(), 14);
+        let merged = RunMerger::open(&runs)
+            .unwrap()
+            .map(|next| {
+                let (ngram, files_lines) = next.unwrap();
+                (ngram.ngrams().to_vec(), files_lines.files_lines().len())
+            })
+            .collect::<Vec<_>>();
+        let expected = std::iter::once((b"aaa".to_vec(), 1))
+            .chain(std::iter::once((b"abc".to_vec(), 7)))
+            .chain((1..7u8).map(|i| (vec![b'a' + i; 3], 1)))
+            .collect::<Vec<_>>();
+        assert_eq!(merged, expected);
+    }
+}
@@ -0,0 +1,178 @@
+use memchr::memmem;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Bytes looked at for a NUL byte, like git does.
+const BINARY_SNIFF_LEN: usize = 8000;
+/// Lines looked at for a generated-file marker, room for a license header.
+const GENERATED_SNIFF_LINES: usize = 20;
+const GENERATED_MARKERS: &[&str] = &["@generated", "DO NOT EDIT"];
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 2 << 20;
+pub const DEFAULT_MAX_LINE_LEN: usize = 4096;
+
+/// Why a file is left out of the index.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SkipReason {
+    /// A NUL byte in its first bytes.
+    Binary,
+    /// Larger than `FileFilter::max_file_size`.
+    Oversized,
+    /// A line longer than `FileFilter::max_line_len`, minified sources mostly.
+    LongLine,
+    /// Marked `@generated` or `DO NOT EDIT` near its top.
+    Generated,
+    /// Missing or not readable.
+    Unreadable,
+}
+
+/// Decides which files are indexed, recorded in `GlobalData` so
+/// `index-update` classifies files the same way as the first build.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileFilter {
+    max_file_size: u64,
+    max_line_len: usize,
+    skip_generated: bool,
+}
+
+/// Number of skipped files by reason, printed after indexing.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SkipSummary {
+    counts: BTreeMap<SkipReason, u32>,
+}
+
+impl FileFilter {
+    pub fn with_max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    pub fn with_max_line_len(mut self, max_line_len: usize) -> Self {
+        self.max_line_len = max_line_len;
+        self
+    }
+
+    pub fn with_skip_generated(mut self, skip_generated: bool) -> Self {
+        self.skip_generated = skip_generated;
+        self
+    }
+
+    /// Checked on the file metadata, before a large file is read at all.
+    pub fn oversized(&self, size: u64) -> bool {
+        size > self.max_file_size
+    }
+
+    /// `None` when `content` is to be indexed.
+    pub fn classify(&self, content: &[u8]) -> Option<SkipReason> {
+        let sniff = &content[..content.len().min(BINARY_SNIFF_LEN)];
+        if self.oversized(content.len() as u64) {
+            Some(SkipReason::Oversized)
+        } else if memchr::memchr(0, sniff).is_some() {
+            Some(SkipReason::Binary)
+        } else if content
+            .split(|byte| *byte == b'\n')
+            .any(|line| line.len() > self.max_line_len)
+        {
+            Some(SkipReason::LongLine)
+        } else if self.skip_generated && FileFilter::is_generated(content) {
+            Some(SkipReason::Generated)
+        } else {
+            None
+        }
+    }
+
+    fn is_generated(content: &[u8]) -> bool {
+        content
+            .split(|byte| *byte == b'\n')
+            .take(GENERATED_SNIFF_LINES)
+            .any(|line| {
+                GENERATED_MARKERS
+                    .iter()
+                    .any(|marker| memmem::find(line, marker.as_bytes()).is_some())
+            })
+    }
+}
+
+impl Default for FileFilter {
+    fn default() -> Self {
+        FileFilter {
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            max_line_len: DEFAULT_MAX_LINE_LEN,
+            skip_generated: true,
+        }
+    }
+}
+
+impl SkipSummary {
+    pub fn insert(&mut self, reason: SkipReason) {
+        *self.counts.entry(reason).or_default() += 1;
+    }
+    pub fn total(&self) -> u32 {
+        self.counts.values().sum()
+    }
+}
+
+impl FromIterator<SkipReason> for SkipSummary {
+    fn from_iter<T: IntoIterator<Item = SkipReason>>(iter: T) -> Self {
+        let mut summary = SkipSummary::default();
+        iter.into_iter().for_each(|reason| summary.insert(reason));
+        summary
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SkipReason::Binary => "binary",
+            SkipReason::Oversized => "oversized",
+            SkipReason::LongLine => "long line",
+            SkipReason::Generated => "generated",
+            SkipReason::Unreadable => "unreadable",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// `skipped 3 files: 1 binary, 2 generated`
+impl fmt::Display for SkipSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "skipped {} files", self.total())?;
+        self.counts
+            .iter()
+            .enumerate()
+            .try_for_each(|(i, (reason, count))| {
+                let separator = if i == 0 { ": " } else { ", " };
+                write!(f, "{}{} {}", separator, count, reason)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_classifies_files() {
+        let filter = FileFilter::default().with_max_file_size(64).with_max_line_len(16);
+        assert_eq!(filter.classify(b"int main() {}\n"), None);
+        assert_eq!(filter.classify(b"ELF\0\x01\x02"), Some(SkipReason::Binary));
+        assert_eq!(filter.classify(&[b'a'; 65]), Some(SkipReason::Oversized));
+        assert_eq!(
+            filter.classify(b"short\nvar a=1,b=2,c=3,d=4;\n"),
+            Some(SkipReason::LongLine)
+        );
+        let generated = b"// Code generated by protoc. DO NOT EDIT.\nint x;\n";
+        let filter = filter.with_max_line_len(64);
+        assert_eq!(filter.classify(generated), Some(SkipReason::Generated));
+        let filter = filter.with_skip_generated(false);
+        assert_eq!(filter.classify(generated), None);
+
+        let summary = [SkipReason::Generated, SkipReason::Binary, SkipReason::Generated]
+            .into_iter()
+            .collect::<SkipSummary>();
+        assert_eq!(summary.to_string(), "skipped 3 files: 1 binary, 2 generated");
+        let summary = [SkipReason::Generated].into_iter().collect::<SkipSummary>();
+        assert_eq!(summary.to_string(), "skipped 1 files: 1 generated");
+        assert_eq!(SkipSummary::default().to_string(), "skipped 0 files");
+    }
+}
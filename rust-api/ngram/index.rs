@@ -1,19 +1,35 @@
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashSet,
     path::{Path, PathBuf},
 };
 use wasm_bindgen::prelude::*;
 
 use crate::ngram::path::{FilePath, GetPath, NgramPath};
+use crate::ngram::posting::PostingIter;
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct NgramIndex {
     ngaram: Box<[u8]>,
+    /// Ngram of the lowercased line, only present in case folded indexes.
+    folded: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct NgramIndexVec(pub Vec<NgramIndex>);
 
+/// How lines and query literals are cut into ngrams.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NgramShape {
+    /// Every window of `n` bytes.
+    Fixed(u8),
+    /// Sparse ngrams of 3 up to `n` bytes: substrings whose two end byte
+    /// pairs weigh more than every byte pair inside them, see `pair_weight`.
+    /// Whether a substring is sparse only depends on its own bytes, so every
+    /// sparse ngram of a literal is indexed on any line holding the literal.
+    Sparse(u8),
+}
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy, Serialize, Deserialize)]
 
 pub struct FileIndex {
@@ -53,6 +69,84 @@ impl NgramIndex {
     pub fn ngrams(&self) -> &[u8] {
         &self.ngaram
     }
+    pub fn is_folded(&self) -> bool {
+        self.folded
+    }
+    pub fn into_folded(self) -> Self {
+        NgramIndex {
+            ngaram: self.ngaram,
+            folded: true,
+        }
+    }
+}
+
+impl NgramShape {
+    /// Ngrams indexed for `bytes`.
+    pub fn line_ngrams(&self, bytes: &[u8]) -> NgramIndexVec {
+        match self {
+            NgramShape::Fixed(n) => NgramIndexVec::from((bytes, *n)),
+            NgramShape::Sparse(n) => NgramIndexVec::from(
+                sparse_spans(bytes, *n)
+                    .into_iter()
+                    .map(|(start, end)| NgramIndex::from(&bytes[start..end]))
+                    .collect::<Vec<_>>(),
+            ),
+        }
+    }
+
+    /// Ngrams a line holding `bytes` must have. Sparse ngrams inside a longer
+    /// one are left out, the longer one implies them and is rarer.
+    pub fn query_ngrams(&self, bytes: &[u8]) -> NgramIndexVec {
+        match self {
+            NgramShape::Fixed(n) => NgramIndexVec::from((bytes, *n)),
+            NgramShape::Sparse(n) => {
+                let mut spans = sparse_spans(bytes, *n);
+                spans.sort_by_key(|(start, end)| (*start, std::cmp::Reverse(*end)));
+                let covering = spans
+                    .into_iter()
+                    .scan(0, |max_end, (start, end)| {
+                        let covered = end <= *max_end;
+                        *max_end = (*max_end).max(end);
+                        Some((!covered).then(|| NgramIndex::from(&bytes[start..end])))
+                    })
+                    .flatten()
+                    .collect::<Vec<_>>();
+                NgramIndexVec::from(covering)
+            }
+        }
+    }
+}
+
+/// Weight of the byte pair `a b`. A bijective hash of the pair, so different
+/// pairs never tie and sparse ngram boundaries look random across code.
+/// Changing it changes the index format.
+fn pair_weight(a: u8, b: u8) -> u32 {
+    ((a as u32) << 8 | b as u32).wrapping_mul(0x9E37_79B1).rotate_left(16)
+}
+
+/// `[start, end)` of every sparse ngram of `bytes` no longer than `max_len`.
+fn sparse_spans(bytes: &[u8], max_len: u8) -> Vec<(usize, usize)> {
+    let weights = bytes
+        .windows(2)
+        .map(|pair| pair_weight(pair[0], pair[1]))
+        .collect::<Vec<_>>();
+    let weights = &weights;
+    let max_len = max_len as usize;
+    (0..weights.len())
+        .flat_map(|first| {
+            // Heaviest pair between the first pair and the current last one.
+            let mut inner = None::<u32>;
+            (first + 1..weights.len().min((first + max_len).saturating_sub(1)))
+                .map_while(move |last| {
+                    // Once an inner pair outweighs the first one no later end is sparse.
+                    inner.is_none_or(|w| w < weights[first]).then_some(())?;
+                    let sparse = inner.is_none_or(|w| w < weights[last]);
+                    inner = inner.max(Some(weights[last]));
+                    Some(sparse.then_some((first, last + 2)))
+                })
+                .flatten()
+        })
+        .collect()
 }
 
 impl FileIndex {
@@ -88,6 +182,28 @@ impl FilesLinesIndex {
     pub fn files_lines(&self) -> &[FileLinesIndex] {
         &self.files_lines_id
     }
+    pub fn is_empty(&self) -> bool {
+        self.files_lines_id.is_empty()
+    }
+    pub fn remove_files(self, files: &HashSet<FileIndex>) -> Self {
+        let files_lines_id = self
+            .files_lines_id
+            .into_iter()
+            .filter(|file_lines| !files.contains(&file_lines.file_id))
+            .collect();
+        FilesLinesIndex { files_lines_id }
+    }
+    /// Every file keeps only its first line, so set operations combine whole files.
+    pub fn files_only(self) -> Self {
+        let files_lines_id = self
+            .files_lines_id
+            .into_iter()
+            .map(|file_lines| {
+                FileLinesIndex::from((file_lines.file_id, LinesIndex::from(vec![LineIndex::from(0)])))
+            })
+            .collect();
+        FilesLinesIndex { files_lines_id }
+    }
 }
 
 impl From<u32> for FileIndex {
@@ -100,6 +216,7 @@ impl From<&[u8]> for NgramIndex {
     fn from(bytes: &[u8]) -> Self {
         NgramIndex {
             ngaram: bytes.into(),
+            folded: false,
         }
     }
 }
@@ -123,6 +240,39 @@ impl From<(&[u8], u8)> for NgramIndexVec {
     }
 }
 
+/// Ngrams of the raw bytes of one line, plus the ngrams of the lowercased
+/// line when `case_fold`.
+impl From<(&[u8], NgramShape, bool)> for NgramIndexVec {
+    fn from((line, shape, case_fold): (&[u8], NgramShape, bool)) -> Self {
+        let ngrams = shape.line_ngrams(line).0;
+        match case_fold {
+            true => {
+                let lower = fold_case_bytes(line);
+                let folded = shape
+                    .line_ngrams(&lower)
+                    .0
+                    .into_iter()
+                    .map(NgramIndex::into_folded);
+                NgramIndexVec::from(ngrams.into_iter().chain(folded).collect::<Vec<_>>())
+            }
+            false => NgramIndexVec(ngrams),
+        }
+    }
+}
+
+/// Lowercase char by char, so a query literal folds the same way as the line it is in.
+pub fn fold_case(text: &str) -> String {
+    text.chars().flat_map(char::to_lowercase).collect()
+}
+
+/// `fold_case` of UTF-8 bytes, other encodings only fold ASCII.
+pub fn fold_case_bytes(bytes: &[u8]) -> Vec<u8> {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => fold_case(text).into_bytes(),
+        Err(_) => bytes.to_ascii_lowercase(),
+    }
+}
+
 impl From<u32> for LineIndex {
     fn from(value: u32) -> Self {
         LineIndex { line: value }
@@ -212,6 +362,67 @@ impl SetCalculate for FilesLinesIndex {
         .collect();
         FilesLinesIndex { files_lines_id }
     }
+
+    /// Per file, the lines of `a` not in `b`, files left without lines are dropped.
+    fn difference(a: Self, b: Self) -> Self {
+        let files_lines_id = a
+            .files_lines_id
+            .into_iter()
+            .filter_map(|a_file_lines| {
+                let b_file_lines = b
+                    .files_lines_id
+                    .binary_search_by_key(&a_file_lines.file_id, |b_file_lines| b_file_lines.file_id)
+                    .ok()
+                    .map(|index| b.files_lines_id[index].lines_id.clone());
+                let lines = match b_file_lines {
+                    Some(b_lines) => SetCalculate::difference(a_file_lines.lines_id, b_lines),
+                    None => a_file_lines.lines_id,
+                };
+                (!lines.lines_id.is_empty())
+                    .then_some(FileLinesIndex::from((a_file_lines.file_id, lines)))
+            })
+            .collect();
+        FilesLinesIndex { files_lines_id }
+    }
+}
+
+impl<'a> SetCalculateIter<PostingIter<'a>> for FilesLinesIndex {
+    fn union_iter(a: Self, b: PostingIter<'a>) -> Self {
+        let mut a_iter = a.files_lines_id.into_iter().peekable();
+        let mut b_iter = b.peekable();
+        let files_lines_id = std::iter::from_fn(|| match (a_iter.peek(), b_iter.peek()) {
+            (Some(a_file_lines), Some(b_file_lines)) => {
+                if a_file_lines.file_id == b_file_lines.file_id {
+                    let a_file_lines = a_iter.next()?;
+                    let b_file_lines = b_iter.next()?;
+                    let lines = SetCalculate::union(a_file_lines.lines_id, b_file_lines.lines_id);
+                    Some(FileLinesIndex::from((a_file_lines.file_id, lines)))
+                } else if a_file_lines.file_id < b_file_lines.file_id {
+                    a_iter.next()
+                } else {
+                    b_iter.next()
+                }
+            }
+            (Some(_), None) => a_iter.next(),
+            (None, _) => b_iter.next(),
+        })
+        .collect();
+        FilesLinesIndex { files_lines_id }
+    }
+
+    fn intersection_iter(a: Self, mut b: PostingIter<'a>) -> Self {
+        let files_lines_id = a
+            .files_lines_id
+            .into_iter()
+            .filter_map(|a_file_lines| {
+                let b_file_lines = b.seek(&a_file_lines.file_id)?;
+                let lines = SetCalculate::intersection(a_file_lines.lines_id, b_file_lines.lines_id);
+                (!lines.lines_id.is_empty())
+                    .then_some(FileLinesIndex::from((a_file_lines.file_id, lines)))
+            })
+            .collect();
+        FilesLinesIndex { files_lines_id }
+    }
 }
 
 impl SetCalculate for LinesIndex {
@@ -246,11 +457,28 @@ impl SetCalculate for LinesIndex {
         .collect();
         LinesIndex { lines_id }
     }
+
+    fn difference(a: Self, b: Self) -> Self {
+        let lines_id = a
+            .lines_id
+            .into_iter()
+            .filter(|line| b.lines_id.binary_search(line).is_err())
+            .collect();
+        LinesIndex { lines_id }
+    }
 }
 
 pub trait SetCalculate {
     fn union(a: Self, b: Self) -> Self;
     fn intersection(a: Self, b: Self) -> Self;
+    /// Entries of `a` not in `b`.
+    fn difference(a: Self, b: Self) -> Self;
+}
+
+/// Set operations against a lazily decoded stream, `b` is never collected.
+pub trait SetCalculateIter<I> {
+    fn union_iter(a: Self, b: I) -> Self;
+    fn intersection_iter(a: Self, b: I) -> Self;
 }
 
 impl GetPath for NgramIndex {
@@ -315,6 +543,55 @@ mod tests {
         );
     }
 
+    #[test]
+    fn files_lines_index_remove_files() {
+        let a = FilesLinesIndex::from(vec![
+            FileLinesIndex::from((
+                FileIndex::from(1),
+                LinesIndex::from(vec![LineIndex::from(1)]),
+            )),
+            FileLinesIndex::from((
+                FileIndex::from(2),
+                LinesIndex::from(vec![LineIndex::from(2)]),
+            )),
+        ]);
+        let removed = HashSet::from([FileIndex::from(1), FileIndex::from(3)]);
+        let b = a.remove_files(&removed);
+        assert_eq!(
+            b.files_lines(),
+            &vec![FileLinesIndex::from((
+                FileIndex::from(2),
+                LinesIndex::from(vec![LineIndex::from(2)])
+            ))]
+        );
+        assert!(b.remove_files(&HashSet::from([FileIndex::from(2)])).is_empty());
+    }
+
+    #[test]
+    fn files_lines_index_set_calculate_iter() {
+        let file_lines = |id: u32, lines: &[u32]| {
+            FileLinesIndex::from((
+                FileIndex::from(id),
+                LinesIndex::from(lines.iter().map(|l| LineIndex::from(*l)).collect::<Vec<_>>()),
+            ))
+        };
+        let a = FilesLinesIndex::from(vec![file_lines(1, &[1, 2]), file_lines(3, &[4])]);
+        let b = FilesLinesIndex::from(vec![
+            file_lines(1, &[2, 3]),
+            file_lines(2, &[1]),
+            file_lines(3, &[5]),
+        ]);
+        let posting = crate::ngram::posting::Posting::from(&b);
+        assert_eq!(
+            FilesLinesIndex::intersection_iter(a.clone(), posting.iter()),
+            FilesLinesIndex::intersection(a.clone(), b.clone())
+        );
+        assert_eq!(
+            FilesLinesIndex::union_iter(a.clone(), posting.iter()),
+            FilesLinesIndex::union(a, b)
+        );
+    }
+
     #[test]
     fn files_ines_index_from() {
         let a = FilesLinesIndex::from(vec![
@@ -339,4 +616,68 @@ mod tests {
             ))]
         );
     }
+
+    #[test]
+    fn sparse_ngrams_cover_queries() {
+        let line = b"    let hello_world = make_hello_world(42);";
+        let spans = sparse_spans(line, 8);
+        // Brute force the definition: inner pairs weigh less than both end pairs.
+        let weight = |i: usize| pair_weight(line[i], line[i + 1]);
+        let expected = (0..line.len())
+            .flat_map(|start| (start + 3..=line.len().min(start + 8)).map(move |end| (start, end)))
+            .filter(|(start, end)| {
+                (start + 1..end - 2).all(|i| weight(i) < weight(*start) && weight(i) < weight(end - 2))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(spans, expected);
+        assert!(line.windows(3).all(|gram| spans.iter().any(|(s, e)| &line[*s..*e] == gram)));
+
+        let shape = NgramShape::Sparse(8);
+        let indexed = shape.line_ngrams(line).0;
+        let query = shape.query_ngrams(b"hello_world").0;
+        assert!(query.iter().all(|ngram| indexed.contains(ngram)));
+        assert!(query.len() < b"hello_world".windows(3).count());
+        assert!(query.iter().all(|ngram| (3..=8).contains(&ngram.ngrams().len())));
+        assert!(shape.query_ngrams(b"ab").0.is_empty());
+    }
+
+    #[test]
+    fn ngram_index_vec_case_fold() {
+        let plain = NgramIndexVec::from((&b"AbC"[..], NgramShape::Fixed(2), false));
+        assert!(plain.0.iter().all(|ngram| !ngram.is_folded()));
+        assert_eq!(plain.0.len(), 2);
+
+        let folded = NgramIndexVec::from((&b"AbC"[..], NgramShape::Fixed(2), true));
+        let lower = folded
+            .0
+            .iter()
+            .filter(|ngram| ngram.is_folded())
+            .map(|ngram| ngram.ngrams().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(lower, vec![b"ab".to_vec(), b"bc".to_vec()]);
+        assert_eq!(folded.0.len(), 4);
+        assert_ne!(NgramIndex::from(&b"ab"[..]), NgramIndex::from(&b"ab"[..]).into_folded());
+    }
+
+    #[test]
+    fn files_lines_index_difference() {
+        let lines = |ids: &[u32]| LinesIndex::from(ids.iter().map(|id| LineIndex::from(*id)).collect::<Vec<_>>());
+        let a = FilesLinesIndex::from(vec![
+            FileLinesIndex::from((FileIndex::from(1), lines(&[1, 2, 3]))),
+            FileLinesIndex::from((FileIndex::from(2), lines(&[4]))),
+            FileLinesIndex::from((FileIndex::from(3), lines(&[5]))),
+        ]);
+        let b = FilesLinesIndex::from(vec![
+            FileLinesIndex::from((FileIndex::from(1), lines(&[2]))),
+            FileLinesIndex::from((FileIndex::from(2), lines(&[4]))),
+        ]);
+        let c = FilesLinesIndex::difference(a, b);
+        assert_eq!(
+            c.files_lines(),
+            &vec![
+                FileLinesIndex::from((FileIndex::from(1), lines(&[1, 3]))),
+                FileLinesIndex::from((FileIndex::from(3), lines(&[5]))),
+            ]
+        );
+    }
 }
@@ -0,0 +1,298 @@
+use crate::ngram::search::{SearchOneFileLinesContentResult, SearchOneLineContentResult};
+use anyhow::Result;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::Serialize;
+use std::time::Duration;
+
+/// One line of ripgrep's `--json` output, see `rg --json`.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", content = "data", rename_all = "lowercase")]
+pub enum JsonMessage {
+    Begin(JsonBegin),
+    Match(JsonLine),
+    Context(JsonLine),
+    End(JsonEnd),
+    Summary(JsonSummary),
+}
+
+/// ripgrep wraps every path and line as `{"text": ...}`, or as base64
+/// `{"bytes": ...}` when it is no valid UTF-8.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum JsonText {
+    Text(String),
+    Bytes(String),
+}
+
+#[derive(Serialize, Debug)]
+pub struct JsonBegin {
+    path: JsonText,
+}
+
+#[derive(Serialize, Debug)]
+pub struct JsonLine {
+    path: JsonText,
+    lines: JsonText,
+    line_number: u32,
+    absolute_offset: u64,
+    submatches: Vec<JsonSubmatch>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct JsonSubmatch {
+    #[serde(rename = "match")]
+    matched: JsonText,
+    start: u32,
+    end: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct JsonEnd {
+    path: JsonText,
+    binary_offset: Option<u64>,
+    stats: JsonStats,
+}
+
+#[derive(Serialize, Debug)]
+pub struct JsonSummary {
+    elapsed_total: JsonDuration,
+    stats: JsonStats,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct JsonStats {
+    elapsed: JsonDuration,
+    searches: u64,
+    searches_with_match: u64,
+    bytes_searched: u64,
+    bytes_printed: u64,
+    matched_lines: u64,
+    matches: u64,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct JsonDuration {
+    secs: u64,
+    nanos: u32,
+    human: String,
+}
+
+/// Turns search results into ripgrep's message stream and sums the stats of
+/// every file for the final `summary`.
+#[derive(Default)]
+pub struct JsonPrinter {
+    stats: JsonStats,
+    elapsed: Duration,
+}
+
+impl JsonPrinter {
+    /// `begin`, `match`/`context` and `end` lines of a searched file, nothing
+    /// when it has no match, like ripgrep.
+    pub fn file(
+        &mut self,
+        path: &str,
+        file: &SearchOneFileLinesContentResult,
+        elapsed: Duration,
+    ) -> Result<Vec<String>> {
+        self.stats.searches += 1;
+        self.stats.bytes_searched += file.searched_bytes();
+        self.elapsed += elapsed;
+        if file.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut lines = std::iter::once(JsonMessage::Begin(JsonBegin {
+            path: JsonText::from(path),
+        }))
+        .chain(file.groups().into_iter().flatten().map(|line| {
+            let json_line = JsonLine::from((path, line));
+            match line.is_context() {
+                true => JsonMessage::Context(json_line),
+                false => JsonMessage::Match(json_line),
+            }
+        }))
+        .map(|message| serde_json::to_string(&message))
+        .collect::<serde_json::Result<Vec<_>>>()?;
+        let stats = JsonStats {
+            elapsed: JsonDuration::from(elapsed),
+            searches: 1,
+            searches_with_match: 1,
+            bytes_searched: file.searched_bytes(),
+            bytes_printed: lines.iter().map(|line| line.len() as u64 + 1).sum(),
+            matched_lines: file.lines().len() as u64,
+            matches: file
+                .lines()
+                .iter()
+                .map(|line| line.match_range().len() as u64)
+                .sum(),
+        };
+        self.stats.searches_with_match += 1;
+        self.stats.bytes_printed += stats.bytes_printed;
+        self.stats.matched_lines += stats.matched_lines;
+        self.stats.matches += stats.matches;
+        lines.push(serde_json::to_string(&JsonMessage::End(JsonEnd {
+            path: JsonText::from(path),
+            binary_offset: None,
+            stats,
+        }))?);
+        Ok(lines)
+    }
+
+    /// The closing `summary` line, `elapsed_total` is the wall time of the whole search.
+    pub fn summary(&self, elapsed_total: Duration) -> Result<String> {
+        let stats = JsonStats {
+            elapsed: JsonDuration::from(self.elapsed),
+            ..self.stats.clone()
+        };
+        let summary = JsonMessage::Summary(JsonSummary {
+            elapsed_total: JsonDuration::from(elapsed_total),
+            stats,
+        });
+        Ok(serde_json::to_string(&summary)?)
+    }
+}
+
+impl From<&str> for JsonText {
+    fn from(text: &str) -> Self {
+        JsonText::Text(text.to_string())
+    }
+}
+
+impl From<&[u8]> for JsonText {
+    fn from(bytes: &[u8]) -> Self {
+        JsonText::Bytes(STANDARD.encode(bytes))
+    }
+}
+
+/// Lines are printed with their line end, offsets are bytes of the line in
+/// the file like ripgrep. Lines of no valid UTF-8 are printed as bytes.
+impl From<(&str, &SearchOneLineContentResult)> for JsonLine {
+    fn from((path, line): (&str, &SearchOneLineContentResult)) -> Self {
+        let (lines, submatches) = match line.raw_content() {
+            Some(raw) => {
+                let submatches = line
+                    .raw_match_range()
+                    .iter()
+                    .map(|(start, end)| JsonSubmatch {
+                        matched: JsonText::from(&raw[*start as usize..*end as usize]),
+                        start: *start,
+                        end: *end,
+                    })
+                    .collect();
+                let lines = [raw, line.line_end().as_bytes()].concat();
+                (JsonText::from(lines.as_slice()), submatches)
+            }
+            None => {
+                let content = line.content();
+                let submatches = line
+                    .match_range()
+                    .iter()
+                    .map(|(start, end)| {
+                        let matched = content.get(*start as usize..*end as usize);
+                        JsonSubmatch {
+                            matched: JsonText::from(matched.unwrap_or_default()),
+                            start: *start,
+                            end: *end,
+                        }
+                    })
+                    .collect();
+                let lines = format!("{}{}", content, line.line_end());
+                (JsonText::from(lines.as_str()), submatches)
+            }
+        };
+        JsonLine {
+            path: JsonText::from(path),
+            lines,
+            line_number: line.line_num(),
+            absolute_offset: line.absolute_offset(),
+            submatches,
+        }
+    }
+}
+
+impl From<Duration> for JsonDuration {
+    fn from(duration: Duration) -> Self {
+        JsonDuration {
+            secs: duration.as_secs(),
+            nanos: duration.subsec_nanos(),
+            human: format!("{:.6}s", duration.as_secs_f64()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ngram::data::{GlobalData, IndexHeader};
+    use crate::ngram::encoding::TextEncoding;
+    use crate::ngram::index::FileIndex;
+    use crate::ngram::search::{
+        SearchEngine, SearchOneFilesLinesEnumResult, SearchOneFilesLinesStructResult,
+        SearchOneQueryResult, SearchOptions,
+    };
+    use serde_json::Value;
+    use std::collections::HashMap;
+
+    #[test]
+    fn json_printer_follows_ripgrep_messages() {
+        let global = GlobalData::from((IndexHeader::from((3, 0)), HashMap::new()));
+        let engine = SearchEngine::from(global);
+        let options = SearchOptions::default().with_context(1, 0);
+        let one = engine.search_with("hit", &options).unwrap();
+        let result = SearchOneQueryResult::from(SearchOneFilesLinesStructResult {
+            enum_result: SearchOneFilesLinesEnumResult::ALL,
+        });
+        let lines = vec!["x", "a hit hit"];
+        // The first line ends in `\r\n`.
+        let file = ("a.c", lines, TextEncoding::Utf8, vec![0u32]);
+        let file = postcard::to_stdvec(&file).unwrap();
+        let file = one
+            .file_lines_match(FileIndex::from(0), file, &result)
+            .unwrap();
+
+        let mut printer = JsonPrinter::default();
+        let lines = printer
+            .file("a.c", &file, Duration::from_millis(1))
+            .unwrap();
+        let messages = lines
+            .iter()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        let types = messages
+            .iter()
+            .map(|m| m["type"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(types, vec!["begin", "context", "match", "end"]);
+        assert_eq!(messages[1]["data"]["lines"]["text"], "x\r\n");
+        let matched = &messages[2]["data"];
+        assert_eq!(matched["path"]["text"], "a.c");
+        assert_eq!(matched["lines"]["text"], "a hit hit\n");
+        assert_eq!(matched["line_number"], 2);
+        assert_eq!(matched["absolute_offset"], 3);
+        assert_eq!(matched["submatches"][1]["match"]["text"], "hit");
+        assert_eq!(matched["submatches"][1]["start"], 6);
+        assert_eq!(messages[3]["data"]["stats"]["matches"], 2);
+
+        let summary = serde_json::from_str::<Value>(&printer.summary(Duration::ZERO).unwrap());
+        let summary = summary.unwrap();
+        assert_eq!(summary["type"], "summary");
+        assert_eq!(summary["data"]["stats"]["bytes_searched"], 13);
+
+        // Offsets of a Latin-1 line count its bytes in the file, not the decoded text.
+        let lines = vec![b"caf\xe9 hit".to_vec()];
+        let file = ("b.c", lines, TextEncoding::Latin1, Vec::<u32>::new());
+        let file = postcard::to_stdvec(&file).unwrap();
+        let file = one
+            .file_lines_match(FileIndex::from(1), file, &result)
+            .unwrap();
+        let lines = printer.file("b.c", &file, Duration::ZERO).unwrap();
+        let matched = serde_json::from_str::<Value>(&lines[1]).unwrap();
+        let matched = &matched["data"];
+        assert_eq!(matched["lines"]["bytes"], STANDARD.encode(b"caf\xe9 hit\n"));
+        assert_eq!(
+            matched["submatches"][0]["match"]["bytes"],
+            STANDARD.encode(b"hit")
+        );
+        assert_eq!(matched["submatches"][0]["start"], 5);
+    }
+}
@@ -2,4 +2,11 @@ pub mod index;
 pub mod data;
 pub mod path;
 pub mod builder;
-pub mod search;
\ No newline at end of file
+pub mod posting;
+pub mod search;
+pub mod json;
+pub mod rank;
+pub mod explain;
+pub mod external;
+pub mod filter;
+pub mod encoding;
\ No newline at end of file
@@ -1,19 +1,26 @@
 use crate::ngram::{
     builder::FileContent,
-    data::{FileData, FromToData, GlobalData, NgramData},
+    data::{
+        FileData, FilesMetaData, FromToData, GlobalData, NgramData, NgramPackRange, PathTable,
+    },
     index::{FileIndex, NgramIndex},
 };
+use crate::range::Range;
 use anyhow::{Result, anyhow};
 #[allow(unused_imports)]
 use log::{error, info};
 use std::{
     fs,
-    io::Write,
+    io::{Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
 };
 
 pub struct GlobalDataPath;
 
+pub struct FilesMetaPath;
+
+pub struct PathTablePath;
+
 pub struct FilePath<'a> {
     file_index: &'a FileIndex,
 }
@@ -23,6 +30,11 @@ pub struct NgramPath<'a> {
     ngram_index: &'a NgramIndex,
 }
 
+#[derive(Debug)]
+pub struct NgramShardPath {
+    shard: u32,
+}
+
 impl GlobalDataPath {
     pub fn dump(&self, base_path: &Path, global_data: &GlobalData) -> Result<()> {
         let path = self.path(base_path);
@@ -39,6 +51,36 @@ impl GlobalDataPath {
     }
 }
 
+impl FilesMetaPath {
+    pub fn dump(&self, base_path: &Path, files_meta: &FilesMetaData) -> Result<()> {
+        let path = self.path(base_path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| anyhow!("crate files meta file fail. {:?}", e))?;
+        }
+        let mut file = fs::File::create(path.as_path())
+            .map_err(|e| anyhow!("crate files meta file fail. {:?}", e))?;
+        let data = files_meta.to_data()?;
+        file.write_all(&data)?;
+        Ok(())
+    }
+}
+
+impl PathTablePath {
+    pub fn dump(&self, base_path: &Path, path_table: &PathTable) -> Result<()> {
+        let path = self.path(base_path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| anyhow!("crate path table file fail. {:?}", e))?;
+        }
+        let mut file = fs::File::create(path.as_path())
+            .map_err(|e| anyhow!("crate path table file fail. {:?}", e))?;
+        let data = path_table.to_data()?;
+        file.write_all(&data)?;
+        Ok(())
+    }
+}
+
 impl<'a> NgramPath<'a> {
     pub fn dump(&self, base_path: &Path, ngram_data: &NgramData) -> Result<()> {
         let path = self.path(base_path);
@@ -53,6 +95,44 @@ impl<'a> NgramPath<'a> {
         file.write_all(&data)?;
         Ok(())
     }
+    pub fn remove(&self, base_path: &Path) -> Result<()> {
+        let path = self.path(base_path);
+        fs::remove_file(path.as_path())
+            .map_err(|e| anyhow!("remove ngram {:?} file fail. {:?}", self.ngram_index, e))
+    }
+}
+
+impl NgramShardPath {
+    pub fn dump(&self, base_path: &Path, data: &[u8]) -> Result<()> {
+        let path = self.path(base_path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| anyhow!("crate ngram shard {} file fail. {:?}", self.shard, e))?;
+        }
+        let mut file = fs::File::create(path.as_path())
+            .map_err(|e| anyhow!("crate ngram shard {} file fail. {:?}", self.shard, e))?;
+        file.write_all(data)?;
+        Ok(())
+    }
+    /// Adds `data` at the end of the shard, existing ranges are kept.
+    pub fn append(&self, base_path: &Path, data: &[u8]) -> Result<()> {
+        let path = self.path(base_path);
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(path.as_path())
+            .map_err(|e| anyhow!("open ngram shard {} file fail. {:?}", self.shard, e))?;
+        file.write_all(data)?;
+        Ok(())
+    }
+    pub fn read(&self, base_path: &Path, range: &Range) -> Result<Vec<u8>> {
+        let path = self.path(base_path);
+        let mut file = fs::File::open(path.as_path())
+            .map_err(|e| anyhow!("open ngram shard {} file fail. {:?}", self.shard, e))?;
+        let mut buffer = vec![0; range.len as usize];
+        file.seek(SeekFrom::Start(range.start))?;
+        file.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
 }
 
 impl<'a> FilePath<'a> {
@@ -67,6 +147,11 @@ impl<'a> FilePath<'a> {
         file.write_all(&data)?;
         Ok(())
     }
+    pub fn remove(&self, base_path: &Path) -> Result<()> {
+        let path_dir = self.path(base_path);
+        fs::remove_dir_all(&path_dir)
+            .map_err(|e| anyhow!("remove file {:?} fail. {:?}", self.file_index, e))
+    }
 }
 
 impl GetPath for GlobalDataPath {
@@ -75,6 +160,18 @@ impl GetPath for GlobalDataPath {
     }   
 }
 
+impl GetPath for FilesMetaPath {
+    fn path(&self, base_path: &Path) -> PathBuf {
+        base_path.join("files.meta")
+    }
+}
+
+impl GetPath for PathTablePath {
+    fn path(&self, base_path: &Path) -> PathBuf {
+        base_path.join("paths.data")
+    }
+}
+
 impl<'a> GetPath for NgramPath<'a> {
     fn path(&self, base_path: &Path) -> PathBuf {
         let ngrams = self.ngram_index.ngrams();
@@ -82,11 +179,22 @@ impl<'a> GetPath for NgramPath<'a> {
             .into_iter()
             .map(|u| u.to_string())
             .collect::<PathBuf>();
-        let path = base_path.join("ngrams").join(ans.as_path());
+        let dir = match self.ngram_index.is_folded() {
+            true => "ngrams-folded",
+            false => "ngrams",
+        };
+        // One directory per byte and a leaf file, so an ngram can prefix a longer sparse one.
+        let path = base_path.join(dir).join(ans.as_path()).join("ngram");
         path
     }
 }
 
+impl GetPath for NgramShardPath {
+    fn path(&self, base_path: &Path) -> PathBuf {
+        base_path.join("packs").join(format!("{}.pack", self.shard))
+    }
+}
+
 impl<'a> GetPath for FilePath<'a> {
     fn path(&self, base_path: &Path) -> PathBuf {
         let id = self.file_index.file_id();
@@ -105,6 +213,18 @@ impl From<()> for  GlobalDataPath {
     }
 }
 
+impl From<()> for FilesMetaPath {
+    fn from(_: ()) -> Self {
+        FilesMetaPath
+    }
+}
+
+impl From<()> for PathTablePath {
+    fn from(_: ()) -> Self {
+        PathTablePath
+    }
+}
+
 impl<'a> From<&'a NgramIndex> for NgramPath<'a> {
     fn from(ngram: &'a NgramIndex) -> Self {
         NgramPath {
@@ -113,6 +233,20 @@ impl<'a> From<&'a NgramIndex> for NgramPath<'a> {
     }
 }
 
+impl From<u32> for NgramShardPath {
+    fn from(shard: u32) -> Self {
+        NgramShardPath { shard }
+    }
+}
+
+impl From<&NgramPackRange> for NgramShardPath {
+    fn from(range: &NgramPackRange) -> Self {
+        NgramShardPath {
+            shard: range.shard(),
+        }
+    }
+}
+
 impl<'a> From<&'a FileIndex> for FilePath<'a> {
     fn from(file_index: &'a FileIndex) -> Self {
         FilePath { file_index }
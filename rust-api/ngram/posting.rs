@@ -0,0 +1,232 @@
+use crate::ngram::index::{FileIndex, FileLinesIndex, FilesLinesIndex, LineIndex, LinesIndex};
+use anyhow::{Result, anyhow};
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use std::io::{Read, Write};
+
+/// Payloads smaller than this are never deflated, the zlib framing would eat the gain.
+const DEFLATE_MIN_LEN: usize = 256;
+const CODEC_VARINT: u8 = 0;
+const CODEC_DEFLATE: u8 = 1;
+
+/// Encoded posting list of one ngram.
+///
+/// Layout, every number is a LEB128 varint:
+/// `file_count`, then per file `file_id - prev_file_id`, `block_len` and a
+/// line block of `line_count` followed by `line_id - prev_line_id` deltas.
+/// `block_len` lets `PostingIter::seek` jump over files it does not need.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Posting {
+    data: Vec<u8>,
+}
+
+pub struct PostingIter<'a> {
+    data: &'a [u8],
+    remain: u64,
+    file_id: u32,
+}
+
+impl Posting {
+    pub fn iter(&self) -> PostingIter<'_> {
+        let mut data = self.data.as_slice();
+        let remain = read_varint(&mut data).unwrap_or(0);
+        PostingIter {
+            data,
+            remain,
+            file_id: 0,
+        }
+    }
+
+    /// Encode with a leading codec byte, deflated when that is smaller.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        if self.data.len() >= DEFLATE_MIN_LEN {
+            let mut encoder = DeflateEncoder::new(vec![CODEC_DEFLATE], Compression::default());
+            encoder.write_all(&self.data)?;
+            let deflated = encoder.finish()?;
+            if deflated.len() < self.data.len() {
+                return Ok(deflated);
+            }
+        }
+        let mut ret = Vec::with_capacity(self.data.len() + 1);
+        ret.push(CODEC_VARINT);
+        ret.extend_from_slice(&self.data);
+        Ok(ret)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        match bytes.split_first() {
+            Some((&CODEC_VARINT, data)) => Ok(Posting {
+                data: data.to_vec(),
+            }),
+            Some((&CODEC_DEFLATE, data)) => {
+                let mut ret = Vec::new();
+                DeflateDecoder::new(data).read_to_end(&mut ret)?;
+                Ok(Posting { data: ret })
+            }
+            Some((codec, _)) => Err(anyhow!("unknown posting codec {}", codec)),
+            None => Err(anyhow!("empty posting data")),
+        }
+    }
+}
+
+impl PostingIter<'_> {
+    /// Entry of `file_index` if present. Files before it are skipped without
+    /// decoding their lines, the iterator stops in front of the next larger file.
+    pub fn seek(&mut self, file_index: &FileIndex) -> Option<FileLinesIndex> {
+        while self.remain > 0 {
+            let saved = (self.data, self.remain, self.file_id);
+            let (file_id, block) = self.next_block()?;
+            if file_id == file_index.file_id() {
+                return Self::decode_block(file_id, block);
+            } else if file_id > file_index.file_id() {
+                (self.data, self.remain, self.file_id) = saved;
+                return None;
+            }
+        }
+        None
+    }
+
+    fn next_block(&mut self) -> Option<(u32, &[u8])> {
+        self.remain -= 1;
+        self.file_id += read_varint(&mut self.data)? as u32;
+        let block_len = read_varint(&mut self.data)? as usize;
+        let (block, rest) = self.data.split_at_checked(block_len)?;
+        self.data = rest;
+        Some((self.file_id, block))
+    }
+
+    fn decode_block(file_id: u32, mut block: &[u8]) -> Option<FileLinesIndex> {
+        let count = read_varint(&mut block)?;
+        let mut line = 0u32;
+        let lines = (0..count)
+            .map(|_| {
+                line += read_varint(&mut block)? as u32;
+                Some(LineIndex::from(line))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(FileLinesIndex::from((
+            FileIndex::from(file_id),
+            LinesIndex::from(lines),
+        )))
+    }
+}
+
+impl Iterator for PostingIter<'_> {
+    type Item = FileLinesIndex;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.remain {
+            0 => None,
+            _ => {
+                let (file_id, block) = self.next_block()?;
+                Self::decode_block(file_id, block)
+            }
+        }
+    }
+}
+
+impl From<&FilesLinesIndex> for Posting {
+    fn from(value: &FilesLinesIndex) -> Self {
+        let mut data = Vec::new();
+        write_varint(&mut data, value.files_lines().len() as u64);
+        let mut file_id = 0;
+        let mut block = Vec::new();
+        value.files_lines().iter().for_each(|file_lines| {
+            let id = file_lines.file_id().file_id();
+            write_varint(&mut data, (id - file_id) as u64);
+            file_id = id;
+
+            block.clear();
+            let lines = file_lines.lines_index().lines();
+            write_varint(&mut block, lines.len() as u64);
+            let mut line_id = 0;
+            lines.iter().for_each(|line| {
+                write_varint(&mut block, (line.line_id() - line_id) as u64);
+                line_id = line.line_id();
+            });
+            write_varint(&mut data, block.len() as u64);
+            data.extend_from_slice(&block);
+        });
+        Posting { data }
+    }
+}
+
+impl From<&Posting> for FilesLinesIndex {
+    fn from(value: &Posting) -> Self {
+        FilesLinesIndex::from(value.iter().collect::<Vec<_>>())
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let (byte, rest) = data.split_first()?;
+        *data = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+        shift += 7;
+        if shift >= 64 {
+            return None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files_lines(entries: &[(u32, &[u32])]) -> FilesLinesIndex {
+        let files = entries
+            .iter()
+            .map(|(file_id, lines)| {
+                let lines = lines.iter().map(|line| LineIndex::from(*line)).collect::<Vec<_>>();
+                FileLinesIndex::from((FileIndex::from(*file_id), LinesIndex::from(lines)))
+            })
+            .collect::<Vec<_>>();
+        FilesLinesIndex::from(files)
+    }
+
+    #[test]
+    fn posting_round_trip() {
+        let index = files_lines(&[(0, &[0, 5, 300]), (7, &[1]), (70000, &[2, 3])]);
+        let posting = Posting::from(&index);
+        let bytes = posting.to_bytes().unwrap();
+        assert_eq!(bytes[0], CODEC_VARINT);
+        let decoded = Posting::from_bytes(&bytes).unwrap();
+        assert_eq!(FilesLinesIndex::from(&decoded), index);
+    }
+
+    #[test]
+    fn posting_deflates_large_lists() {
+        let lines = (0..2000).collect::<Vec<u32>>();
+        let entries = (0..20).map(|id| (id * 3, lines.as_slice())).collect::<Vec<_>>();
+        let index = files_lines(&entries);
+        let bytes = Posting::from(&index).to_bytes().unwrap();
+        assert_eq!(bytes[0], CODEC_DEFLATE);
+        let decoded = Posting::from_bytes(&bytes).unwrap();
+        assert_eq!(FilesLinesIndex::from(&decoded), index);
+    }
+
+    #[test]
+    fn posting_seek_skips_files() {
+        let index = files_lines(&[(1, &[1]), (4, &[2]), (9, &[3])]);
+        let posting = Posting::from(&index);
+        let mut iter = posting.iter();
+        assert!(iter.seek(&FileIndex::from(3)).is_none());
+        assert_eq!(iter.seek(&FileIndex::from(4)).unwrap().file_id().file_id(), 4);
+        assert_eq!(iter.seek(&FileIndex::from(9)).unwrap().file_id().file_id(), 9);
+        assert!(iter.seek(&FileIndex::from(10)).is_none());
+    }
+}
@@ -0,0 +1,136 @@
+use crate::lsp::data::DefinitionsData;
+use crate::ngram::index::LinesIndex;
+use std::collections::{BTreeSet, HashMap};
+
+/// Candidate lines past this many do not make a file more relevant.
+const MATCH_COUNT_CAP: u32 = 100;
+const MATCH_WEIGHT: i64 = 10;
+const DEFINITION_BONUS: i64 = 400;
+const TEST_PENALTY: i64 = 200;
+const DEPTH_PENALTY: i64 = 5;
+/// Directories holding tests, a file below one of them is a test file.
+const TEST_DIRS: &[&str] = &["test", "tests", "testing", "unittest", "unittests", "__tests__"];
+
+/// Relevance signals of a candidate file, all known before its blob is fetched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileRank {
+    /// Candidate lines of the posting lists, verified matches are not known yet.
+    match_count: u32,
+    /// Directories above the file.
+    depth: u32,
+    is_test: bool,
+    /// Some candidate line holds a definition according to the LSP index.
+    in_definition: bool,
+}
+
+/// Lines holding a definition, by full file name, read from `definition.data`
+/// files of the LSP index. Every go-to-definition location counts, so a file
+/// has to be loaded only for the definitions it refers to.
+#[derive(Debug, Default)]
+pub struct DefinitionLines {
+    lines: HashMap<String, BTreeSet<u32>>,
+}
+
+impl FileRank {
+    /// Higher is more relevant.
+    pub fn score(&self) -> i64 {
+        let mut score = self.match_count.min(MATCH_COUNT_CAP) as i64 * MATCH_WEIGHT
+            - self.depth as i64 * DEPTH_PENALTY;
+        if self.in_definition {
+            score += DEFINITION_BONUS;
+        }
+        if self.is_test {
+            score -= TEST_PENALTY;
+        }
+        score
+    }
+
+    /// `foo_test.cc`, `test_foo.py`, `foo.spec.ts` or anything below a test directory.
+    fn is_test_path(path: &str) -> bool {
+        let mut components = path.split('/').rev();
+        let name = components.next().unwrap_or_default();
+        let stem = name.split('.').next().unwrap_or_default();
+        components.any(|dir| TEST_DIRS.contains(&dir))
+            || stem.ends_with("_test")
+            || stem.ends_with("_unittest")
+            || stem.starts_with("test_")
+            || name.contains(".test.")
+            || name.contains(".spec.")
+    }
+}
+
+impl DefinitionLines {
+    pub fn insert(&mut self, definitions: &DefinitionsData) {
+        definitions
+            .definitions()
+            .iter()
+            .flat_map(|definition| definition.locations())
+            .for_each(|location| {
+                self.lines
+                    .entry(location.file_name().to_string())
+                    .or_default()
+                    .insert(location.range().start.line);
+            });
+    }
+
+    fn contains_any(&self, path: &str, lines: &LinesIndex) -> bool {
+        self.lines.get(path).is_some_and(|definitions| {
+            lines
+                .lines()
+                .iter()
+                .any(|line| definitions.contains(&line.line_id()))
+        })
+    }
+}
+
+/// `path` is `None` without a path table, `lines` is `None` on a full scan.
+impl From<(Option<&str>, Option<&LinesIndex>, &DefinitionLines)> for FileRank {
+    fn from(
+        (path, lines, definitions): (Option<&str>, Option<&LinesIndex>, &DefinitionLines),
+    ) -> Self {
+        let path = path.unwrap_or_default();
+        FileRank {
+            match_count: lines.map_or(0, |lines| lines.lines().len() as u32),
+            depth: path.trim_start_matches('/').matches('/').count() as u32,
+            is_test: FileRank::is_test_path(path),
+            in_definition: lines.is_some_and(|lines| definitions.contains_any(path, lines)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ngram::index::LineIndex;
+
+    fn lines(lines: &[u32]) -> LinesIndex {
+        LinesIndex::from(lines.iter().copied().map(LineIndex::from).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn file_rank_orders_signals() {
+        let none = DefinitionLines::default();
+        let rank = |path: &str, count: &[u32], definitions: &DefinitionLines| {
+            FileRank::from((Some(path), Some(&lines(count)), definitions)).score()
+        };
+        assert!(rank("/src/a.cc", &[1, 2, 3], &none) > rank("/src/a.cc", &[1], &none));
+        assert!(rank("/src/a.cc", &[1], &none) > rank("/src/net/tcp/a.cc", &[1], &none));
+        assert!(rank("/src/a.cc", &[1], &none) > rank("/src/a_test.cc", &[1], &none));
+        assert!(rank("/src/a.cc", &[1], &none) > rank("/tests/a.cc", &[1], &none));
+        assert!(!FileRank::is_test_path("/src/contest/attest.cc"));
+        assert!(FileRank::is_test_path("/web/app.spec.ts"));
+
+        let location = serde_json::json!({
+            "range": {"start": {"line": 4, "character": 0}, "end": {"line": 4, "character": 3}},
+            "file_name": "/src/b.cc",
+        });
+        let data = serde_json::json!({"definitions": [{
+            "range": {"start": {"line": 9, "character": 0}, "end": {"line": 9, "character": 3}},
+            "locations": [location],
+        }]});
+        let mut definitions = DefinitionLines::default();
+        definitions.insert(&serde_json::from_value(data).unwrap());
+        assert!(rank("/src/b.cc", &[4], &definitions) > rank("/src/b.cc", &[5, 6, 7], &definitions));
+        assert_eq!(FileRank::from((None, None, &definitions)).score(), 0);
+    }
+}
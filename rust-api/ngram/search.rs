@@ -1,24 +1,117 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
 
 use anyhow::{Result, anyhow};
 use regex_syntax::{
-    hir::{Hir, HirKind},
-    parse,
+    ParserBuilder,
+    ast::{self, Ast},
+    hir::{Class, Hir, HirKind},
 };
 
 use crate::ngram::{
-    data::{FileData, FromToData, GlobalData, NgramData},
-    index::{FileIndex, FilesLinesIndex, LinesIndex, NgramIndex, NgramIndexVec, SetCalculate},
+    data::{FileData, FromToData, GlobalData, NgramData, NgramPackRange, PathTable},
+    index::{
+        FileIndex, FilesLinesIndex, LineIndex, LinesIndex, NgramIndex, NgramIndexVec,
+        NgramShape, SetCalculate, SetCalculateIter, fold_case_bytes,
+    },
+    explain::{Candidates, PlanNode, PlanStep, QueryPlan},
+    rank::{DefinitionLines, FileRank},
 };
 
 pub struct SearchEngine {
     global_data: GlobalData,
+    scan_limit: Option<usize>,
+    path_table: Option<Arc<PathTable>>,
+}
+
+/// How a pattern is matched, shared by every search of an engine.
+#[derive(Clone, Debug, Default)]
+pub struct SearchOptions {
+    ignore_case: bool,
+    fixed_strings: bool,
+    query: bool,
+    multiline: bool,
+    before_context: u32,
+    after_context: u32,
+    max_count: Option<u32>,
+    path_filters: Vec<PathFilter>,
+}
+
+/// Keeps or drops files by their full file name, see `SearchQuery`.
+#[derive(Clone, Debug)]
+pub struct PathFilter {
+    exclude: bool,
+    re: regex::Regex,
+}
+
+/// A search term split into its leading filters and the pattern.
+///
+/// `path:src/ -path:test lang:cpp foo` searches `foo` in files whose name
+/// matches the regex `src/`, not the regex `test`, with a C++ extension.
+/// `glob:` takes a glob instead, `**` crosses directories and a glob
+/// without `/` matches the last components of the name. Every filter must
+/// hold, the pattern is the rest of the term, kept as is.
+///
+/// The rest may join several patterns with `AND`, `OR` and `NOT`, written
+/// in capitals between spaces. `NOT` binds tightest, then `AND`, then `OR`,
+/// a missing operator means `AND`. The operators work per file: `Mutex AND
+/// unlock NOT lock_guard` are files with lines matching `Mutex` and
+/// `unlock` but no line matching `lock_guard`.
+///
+/// Only parsed with `SearchOptions::with_query`, else the whole term is
+/// one pattern.
+#[derive(Debug)]
+pub struct SearchQuery {
+    terms: Vec<String>,
+    expr: QueryExpr,
+    path_filters: Vec<PathFilter>,
+}
+
+/// Boolean combination of the terms of a query, `Term` indexes `SearchQuery::terms`.
+#[derive(Clone, Debug, PartialEq)]
+enum QueryExpr {
+    Term(usize),
+    And(Vec<QueryExpr>),
+    Or(Vec<QueryExpr>),
+    Not(Box<QueryExpr>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum QueryToken {
+    And,
+    Or,
+    Not,
+    Term(String),
+}
+
+/// Lines matched by one term, line id with the match ranges in it.
+type TermLines = Vec<(u32, Vec<(u32, u32)>)>;
+
+/// One pattern of a query with the ngrams it needs.
+struct SearchTerm {
+    pattern: String,
+    tree: NgramTree,
+    matcher: Matcher,
 }
 
 pub struct SearchOneEngine {
     global_data: GlobalData,
-    tree: NgramTree,
-    re: regex::Regex,
+    terms: Vec<SearchTerm>,
+    expr: QueryExpr,
+    scan_limit: Option<usize>,
+    multiline: bool,
+    before_context: u32,
+    after_context: u32,
+    max_count: Option<u32>,
+    path_filters: Vec<PathFilter>,
+    path_table: Option<Arc<PathTable>>,
+}
+
+/// Verifies candidate lines on their raw bytes, whatever their encoding, a
+/// plain substring search skips the regex engine.
+enum Matcher {
+    Regex(regex::bytes::Regex),
+    Literal(Box<memchr::memmem::Finder<'static>>),
 }
 
 pub struct SearchOneNgramResult {
@@ -29,30 +122,76 @@ pub struct NgramIndexData {
     data: NgramData,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SearchOneFilesLinesStructResult {
     pub enum_result: SearchOneFilesLinesEnumResult,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum SearchOneFilesLinesEnumResult {
     ALL,
     FilesLines(FilesLinesIndex),
 }
 
+/// Candidate files of a whole query, with the candidates of every term
+/// kept to verify the terms one by one.
+#[derive(Debug)]
+pub struct SearchOneQueryResult {
+    files: SearchOneFilesLinesStructResult,
+    terms: Vec<SearchOneFilesLinesStructResult>,
+}
+
 #[derive(Debug)]
 pub struct SearchOneFileLinesContentResult {
     full_file_name: String,
     lines: Vec<SearchOneLineContentResult>,
+    /// Lines around `lines` asked for by `-A/-B/-C`, never a matched line.
+    context: Vec<SearchOneLineContentResult>,
+    /// Size of the verified content, each line counted with its line end.
+    searched_bytes: u64,
 }
 #[derive(Debug, Clone)]
 pub struct SearchOneLineContentResult {
     line_num: u32,
     content: String,
     match_range: Vec<(u32, u32)>,
+    is_context: bool,
+    /// Indexes of the query terms matching this line.
+    terms: Vec<u32>,
+    /// Byte offset of the line start in the file.
+    absolute_offset: u64,
+    /// `\n` or `\r\n`, what ended the line in the file.
+    line_end: &'static str,
+    /// Bytes of the line in the file, only kept when they are no valid UTF-8.
+    raw_content: Option<Vec<u8>>,
+    /// `match_range` in `raw_content`.
+    raw_match_range: Vec<(u32, u32)>,
 }
 
-#[derive(Clone)]
+/// Extensions of the languages `lang:` knows.
+const LANG_EXTENSIONS: &[(&str, &[&str])] = &[
+    ("c", &["c", "h"]),
+    ("cpp", &["c", "cc", "cpp", "cxx", "c++", "h", "hh", "hpp", "hxx", "inl", "ipp"]),
+    ("rust", &["rs"]),
+    ("python", &["py", "pyi"]),
+    ("go", &["go"]),
+    ("java", &["java"]),
+    ("js", &["js", "mjs", "cjs", "jsx"]),
+    ("ts", &["ts", "mts", "tsx"]),
+    ("shell", &["sh", "bash", "zsh"]),
+    ("cmake", &["cmake"]),
+    ("markdown", &["md", "markdown"]),
+];
+const PATH_FILTER_KEYS: &[&str] = &["path:", "glob:", "lang:"];
+
+/// Classes with at most this many characters are expanded into alternatives.
+const CLASS_EXPAND_LIMIT: usize = 32;
+/// Bound of the cross product of expanded literals.
+const LITERAL_SET_BUDGET: usize = 64;
+/// Fetching one file blob costs about as much as this many posting list entries.
+const BLOB_COST: f64 = 64.0;
+
+#[derive(Clone, Debug, PartialEq)]
 enum NgramTree {
     ALL,
     Gram(NgramIndex),
@@ -63,20 +202,93 @@ enum NgramTree {
 impl SearchOneEngine {
     pub fn ngrams(&self) -> NgramIndexVec {
         NgramIndexVec::from(
-            self.tree
-                .ngrams()
-                .0
-                .into_iter()
+            self.terms
+                .iter()
+                .flat_map(|term| term.tree.ngrams().0)
                 .filter(|ngram_index| self.global_data.has_index(ngram_index))
                 .collect::<Vec<_>>(),
         )
     }
 
+    /// Full file name of `file_index` from the path table, without fetching its blob.
+    pub fn path(&self, file_index: &FileIndex) -> Option<String> {
+        self.path_table
+            .as_ref()
+            .and_then(|path_table| path_table.path(file_index))
+    }
+
+    /// Number of patterns in the query, see `SearchQuery`.
+    pub fn terms_len(&self) -> usize {
+        self.terms.len()
+    }
+
+    /// Where the ngram lives inside the pack files, `None` for the one file per ngram layout.
+    pub fn pack_range(&self, ngram_index: &NgramIndex) -> Option<NgramPackRange> {
+        self.global_data.pack_range(ngram_index)
+    }
+
     pub fn files_lines(
         &self,
         ngrams_index: NgramIndexVec,
         datas: Vec<Vec<u8>>,
-    ) -> Result<SearchOneFilesLinesStructResult> {
+    ) -> Result<SearchOneQueryResult> {
+        let index_data = self.ngram_result(ngrams_index, datas)?;
+        let terms = self
+            .terms
+            .iter()
+            .map(|term| term.tree.files_lines(&index_data))
+            .collect::<Vec<_>>();
+        Ok(SearchOneQueryResult {
+            files: self.expr.candidates(&terms),
+            terms,
+        })
+    }
+
+    /// Same inputs as `files_lines`, tells how the candidates come about: the
+    /// ngram tree of every term with the posting list sizes and the candidates
+    /// left after each node, then the file blobs a search would fetch.
+    pub fn explain(&self, ngrams_index: NgramIndexVec, datas: Vec<Vec<u8>>) -> Result<QueryPlan> {
+        let bytes = ngrams_index
+            .0
+            .iter()
+            .zip(datas.iter())
+            .map(|(index, data)| (index.clone(), data.len()))
+            .collect::<HashMap<_, _>>();
+        let index_data = self.ngram_result(ngrams_index, datas)?;
+        let (terms, plans): (Vec<_>, Vec<_>) = self
+            .terms
+            .iter()
+            .map(|term| {
+                let (result, plan) = term.tree.plan(&index_data, &self.global_data, &bytes);
+                (result, (term.pattern.clone(), plan))
+            })
+            .unzip();
+        let result = SearchOneQueryResult {
+            files: self.expr.candidates(&terms),
+            terms,
+        };
+        // Counted without the scan limit, explaining a full scan is no reason to refuse it.
+        let fetch_files = match result.is_all() {
+            true => self
+                .global_data
+                .files()
+                .filter(|file| self.path_allowed(file))
+                .count(),
+            false => self.files(&result)?.len(),
+        };
+        Ok(QueryPlan::from((
+            plans,
+            result.files.candidates(),
+            fetch_files,
+            self.global_data.files().count(),
+        )))
+    }
+
+    fn ngram_result(
+        &self,
+        ngrams_index: NgramIndexVec,
+        datas: Vec<Vec<u8>>,
+    ) -> Result<SearchOneNgramResult> {
         (ngrams_index.0.len() == datas.len())
             .then_some(())
             .ok_or_else(|| anyhow!("ngram index and data length mismatch"))?;
@@ -86,69 +298,379 @@ impl SearchOneEngine {
             .zip(datas.into_iter())
             .filter_map(|(index, data)| {
                 (!data.is_empty()).then_some(
-                    NgramData::from_data(data.as_slice())
-                        .and_then(|data| Ok(NgramIndexData::from((index, data)))),
+                    NgramData::from_data(data.as_slice()).map(|data| {
+                        let data = match self.multiline {
+                            // A match may spread its ngrams over several lines.
+                            true => NgramData::from(data.files_lines().files_only()),
+                            false => data,
+                        };
+                        NgramIndexData::from((index, data))
+                    }),
                 )
             })
             .collect::<Result<Vec<_>>>()?;
-        let index_data = SearchOneNgramResult::from(index_data);
-        Ok(self.tree.files_lines(&index_data))
+        Ok(SearchOneNgramResult::from(index_data))
+    }
+
+    /// Files to fetch for `result`. When the pattern gave no usable ngram
+    /// every indexed file is scanned, as long as it stays under the scan limit.
+    pub fn files(&self, result: &SearchOneQueryResult) -> Result<Vec<FileIndex>> {
+        match result.is_all() {
+            true => {
+                self.check_scan_limit()?;
+                Ok(self
+                    .global_data
+                    .files()
+                    .filter(|file| self.path_allowed(file))
+                    .collect())
+            }
+            false => Ok(result
+                .files()?
+                .into_iter()
+                .filter(|file| self.path_allowed(file))
+                .collect()),
+        }
     }
 
+    /// `len` files of `files` starting at `offset`, so callers can fetch a full scan in bounded batches.
+    pub fn files_batch(
+        &self,
+        result: &SearchOneQueryResult,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<FileIndex>> {
+        match result.is_all() {
+            true => {
+                self.check_scan_limit()?;
+                Ok(self
+                    .global_data
+                    .files()
+                    .filter(|file| self.path_allowed(file))
+                    .skip(offset)
+                    .take(len)
+                    .collect())
+            }
+            false => Ok(self.files(result)?.into_iter().skip(offset).take(len).collect()),
+        }
+    }
+
+    /// `files` ordered by `FileRank`, most relevant first and by file id on a tie.
+    pub fn ranked_files(
+        &self,
+        result: &SearchOneQueryResult,
+        definitions: &DefinitionLines,
+    ) -> Result<Vec<FileIndex>> {
+        let mut files = self
+            .files(result)?
+            .into_iter()
+            .map(|file_index| {
+                let path = self.path(&file_index);
+                let lines = result.files.binary_search_file(&file_index);
+                let rank = FileRank::from((path.as_deref(), lines, definitions));
+                (std::cmp::Reverse(rank.score()), file_index)
+            })
+            .collect::<Vec<_>>();
+        files.sort_unstable();
+        Ok(files.into_iter().map(|(_, file_index)| file_index).collect())
+    }
+
+    /// Page `page`, counted from 0, of `ranked_files` with `page_size` files,
+    /// so only the blobs of the shown files have to be fetched.
+    pub fn ranked_page(
+        &self,
+        result: &SearchOneQueryResult,
+        definitions: &DefinitionLines,
+        page: usize,
+        page_size: usize,
+    ) -> Result<Vec<FileIndex>> {
+        Ok(self
+            .ranked_files(result, definitions)?
+            .into_iter()
+            .skip(page.saturating_mul(page_size))
+            .take(page_size)
+            .collect())
+    }
+
+    /// Verifies every term on `file_index`. When the query holds, the lines
+    /// matched by terms outside a `NOT` are returned.
     pub fn file_lines_match(
         &self,
         file_index: FileIndex,
         file_data: Vec<u8>,
-        result: &SearchOneFilesLinesStructResult,
+        result: &SearchOneQueryResult,
     ) -> Result<SearchOneFileLinesContentResult> {
-        let lines_index = result
-            .binary_search_file(&file_index)
-            .ok_or_else(|| anyhow!("file {} not match", file_index.file_id()))?;
         let file_data = FileData::from_data(&file_data)?;
-        let full_file_name = file_data.full_file_name().to_string();
-        let lines = lines_index
-            .lines()
+        let lines = self.matched_lines(&file_index, &file_data, result, self.max_count)?;
+        Ok(self.content_result(&file_data, lines))
+    }
+
+    /// Number of matched lines in `file_index`, at most the max count.
+    pub fn file_match_count(
+        &self,
+        file_index: FileIndex,
+        file_data: Vec<u8>,
+        result: &SearchOneQueryResult,
+    ) -> Result<u32> {
+        let file_data = FileData::from_data(&file_data)?;
+        let lines = self.matched_lines(&file_index, &file_data, result, self.max_count)?;
+        Ok(lines.len() as u32)
+    }
+
+    /// Whether `file_index` matches at all, verification stops at the first matched line.
+    pub fn file_is_match(
+        &self,
+        file_index: FileIndex,
+        file_data: Vec<u8>,
+        result: &SearchOneQueryResult,
+    ) -> Result<bool> {
+        let file_data = FileData::from_data(&file_data)?;
+        let lines = self.matched_lines(&file_index, &file_data, result, Some(1))?;
+        Ok(!lines.is_empty())
+    }
+}
+
+impl SearchOneEngine {
+    /// The first `limit` lines matched by the terms outside a `NOT`, or none
+    /// when the query does not hold. No term is verified past `limit` lines:
+    /// the first `limit` lines of the merged result are among them.
+    fn matched_lines(
+        &self,
+        file_index: &FileIndex,
+        file_data: &FileData,
+        result: &SearchOneQueryResult,
+        limit: Option<u32>,
+    ) -> Result<Vec<SearchOneLineContentResult>> {
+        (result.is_all() || result.files.binary_search_file(file_index).is_some())
+            .then_some(())
+            .ok_or_else(|| anyhow!("file {} not match", file_index.file_id()))?;
+        let limit = limit.map_or(usize::MAX, |limit| limit as usize);
+        let positive = self.expr.positive_terms(self.terms.len());
+        let hits = self
+            .terms
             .iter()
-            .filter_map(|line_index| {
-                let content = file_data
-                    .lines(line_index)
-                    .map_or_else(
-                        || {
-                            Err(anyhow!(
-                                "file {full_file_name} not have line {}",
-                                line_index.line_num()
-                            ))
-                        },
-                        |line| Ok(line),
-                    )
-                    .and_then(|content| {
-                        let match_range = self
-                            .re
-                            .find_iter(content.as_str())
-                            .map(|m| (m.start() as u32, m.end() as u32))
-                            .collect::<Vec<_>>();
-                        Ok(SearchOneLineContentResult {
-                            line_num: line_index.line_num(),
-                            content: content.to_string(),
-                            match_range,
-                        })
-                    });
-                content.map_or_else(
-                    |e| Some(Err(e)),
-                    |content| {
-                        if content.is_empty() {
-                            None
-                        } else {
-                            Some(Ok(content))
-                        }
-                    },
-                )
+            .zip(result.terms.iter())
+            .zip(positive.iter())
+            .map(|((term, candidates), positive)| {
+                // A term under `NOT` only has to be found once.
+                let limit = match positive {
+                    true => limit,
+                    false => 1,
+                };
+                self.term_match(term, file_index, file_data, candidates, limit)
             })
             .collect::<Result<Vec<_>>>()?;
-        Ok(SearchOneFileLinesContentResult {
-            full_file_name,
+        let hit = hits.iter().map(|lines| !lines.is_empty()).collect::<Vec<_>>();
+        // Line id to its match ranges and the terms matching it.
+        let mut matched = BTreeMap::new();
+        if self.expr.eval(&hit) {
+            hits.into_iter()
+                .enumerate()
+                .filter(|(term, _)| positive[*term])
+                .for_each(|(term, lines)| {
+                    lines.into_iter().for_each(|(line, match_range)| {
+                        let (ranges, terms) =
+                            matched.entry(line).or_insert_with(|| (vec![], vec![]));
+                        ranges.extend(match_range);
+                        terms.push(term as u32);
+                    })
+                });
+        }
+        let lines = matched
+            .into_iter()
+            .map(|(line, (match_range, terms))| {
+                let raw_content = file_data.raw_line(line);
+                let raw_match_range = merge_ranges(match_range);
+                let (content, match_range) = file_data.display_line(line, &raw_match_range);
+                SearchOneLineContentResult {
+                    line_num: LineIndex::from(line).line_num(),
+                    content,
+                    match_range,
+                    is_context: false,
+                    terms,
+                    // Set by `content_result`, which walks all lines once.
+                    absolute_offset: 0,
+                    line_end: file_data.line_end(line),
+                    raw_match_range: match raw_content {
+                        Some(_) => raw_match_range,
+                        None => vec![],
+                    },
+                    raw_content,
+                }
+            })
+            .filter(|line| !line.is_empty())
+            .take(limit)
+            .collect();
+        Ok(lines)
+    }
+
+    /// Lines of `file_index` matching `term`, with their match ranges.
+    /// Only the candidate lines of the term are run through its matcher.
+    fn term_match(
+        &self,
+        term: &SearchTerm,
+        file_index: &FileIndex,
+        file_data: &FileData,
+        candidates: &SearchOneFilesLinesStructResult,
+        limit: usize,
+    ) -> Result<TermLines> {
+        let lines = match candidates.is_all() {
+            true => None,
+            false => match candidates.binary_search_file(file_index) {
+                Some(lines) => Some(lines),
+                None => return Ok(vec![]),
+            },
+        };
+        if self.multiline {
+            let mut lines = Self::match_multiline(&term.matcher, file_data);
+            lines.truncate(limit);
+            return Ok(lines);
+        }
+        let lines_index = match lines {
+            Some(lines) => lines.lines().to_vec(),
+            None => (0..file_data.all_lines().len() as u32)
+                .map(LineIndex::from)
+                .collect::<Vec<_>>(),
+        };
+        lines_index
+            .iter()
+            .filter_map(|line_index| match file_data.lines(line_index) {
+                Some(content) => {
+                    let match_range = term.matcher.match_range(content);
+                    (!match_range.is_empty()).then_some(Ok((line_index.line_id(), match_range)))
+                }
+                None => Some(Err(anyhow!(
+                    "file {} not have line {}",
+                    file_data.full_file_name(),
+                    line_index.line_num()
+                ))),
+            })
+            .take(limit)
+            .collect()
+    }
+
+    /// Runs the matcher over the whole file joined by `\n`, every match is
+    /// cut at line ends and each line gets the part of the match it holds.
+    fn match_multiline(matcher: &Matcher, file_data: &FileData) -> TermLines {
+        let all_lines = file_data.all_lines();
+        // An empty pattern match has no line to land on.
+        if all_lines.is_empty() {
+            return vec![];
+        }
+        let content = all_lines.join(&b'\n');
+        let starts = all_lines
+            .iter()
+            .scan(0, |start, line| {
+                let ret = *start;
+                *start += line.len() + 1;
+                Some(ret)
+            })
+            .collect::<Vec<_>>();
+        let mut match_ranges: Vec<Vec<(u32, u32)>> = vec![vec![]; all_lines.len()];
+        matcher
+            .match_range(&content)
+            .into_iter()
+            .for_each(|(start, end)| {
+                let (start, end) = (start as usize, end as usize);
+                let first = starts.partition_point(|line_start| *line_start <= start) - 1;
+                (first..all_lines.len())
+                    .take_while(|i| *i == first || starts[*i] < end)
+                    .for_each(|i| {
+                        let line_end = starts[i] + all_lines[i].len();
+                        match_ranges[i].push((
+                            (start.max(starts[i]) - starts[i]) as u32,
+                            (end.min(line_end) - starts[i]) as u32,
+                        ));
+                    });
+            });
+        match_ranges
+            .into_iter()
+            .enumerate()
+            .filter(|(_, match_range)| !match_range.is_empty())
+            .map(|(i, match_range)| (i as u32, match_range))
+            .collect()
+    }
+
+    /// Adds the context lines around the matched `lines`.
+    fn content_result(
+        &self,
+        file_data: &FileData,
+        lines: Vec<SearchOneLineContentResult>,
+    ) -> SearchOneFileLinesContentResult {
+        let all_lines = file_data.all_lines();
+        let offsets = all_lines
+            .iter()
+            .enumerate()
+            .scan(0, |offset, (i, line)| {
+                *offset += (line.len() + file_data.line_end(i as u32).len()) as u64;
+                Some(*offset)
+            })
+            .collect::<Vec<_>>();
+        let offset = |line: u32| match line {
+            0 => 0,
+            line => offsets[line as usize - 1],
+        };
+        let matched = lines.iter().map(|line| line.line_num - 1).collect::<BTreeSet<_>>();
+        let context = matched
+            .iter()
+            .flat_map(|line| {
+                let first = line.saturating_sub(self.before_context);
+                let last = line
+                    .saturating_add(self.after_context)
+                    .min(all_lines.len().saturating_sub(1) as u32);
+                first..=last
+            })
+            .filter(|line| !matched.contains(line))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .filter_map(|line| {
+                all_lines.get(line as usize).map(|content| SearchOneLineContentResult {
+                    line_num: LineIndex::from(line).line_num(),
+                    content: file_data.encoding().decode(content).into_owned(),
+                    match_range: vec![],
+                    is_context: true,
+                    terms: vec![],
+                    absolute_offset: offset(line),
+                    line_end: file_data.line_end(line),
+                    raw_content: file_data.raw_line(line),
+                    raw_match_range: vec![],
+                })
+            })
+            .collect();
+        let lines = lines
+            .into_iter()
+            .map(|line| SearchOneLineContentResult {
+                absolute_offset: offset(line.line_num - 1),
+                ..line
+            })
+            .collect();
+        SearchOneFileLinesContentResult {
+            full_file_name: file_data.full_file_name().to_string(),
             lines,
-        })
+            context,
+            searched_bytes: offsets.last().copied().unwrap_or(0),
+        }
+    }
+
+    /// Whether the path filters keep `file`, checked before its blob is fetched.
+    fn path_allowed(&self, file: &FileIndex) -> bool {
+        match self.path_filters.is_empty() {
+            true => true,
+            false => self.path(file).is_some_and(|path| {
+                self.path_filters.iter().all(|filter| filter.matches(&path))
+            }),
+        }
+    }
+
+    fn check_scan_limit(&self) -> Result<()> {
+        let count = self.global_data.header().file_count() as usize;
+        match self.scan_limit {
+            Some(limit) if count > limit => Err(anyhow!(
+                "pattern has no usable ngram, a full scan of {} files is above the scan limit {}",
+                count,
+                limit
+            )),
+            _ => Ok(()),
+        }
     }
 }
 
@@ -164,6 +686,26 @@ impl SearchOneFileLinesContentResult {
     pub fn lines(&self) -> &[SearchOneLineContentResult] {
         &self.lines
     }
+
+    pub fn searched_bytes(&self) -> u64 {
+        self.searched_bytes
+    }
+
+    /// Matched and context lines by line number, split where lines are skipped.
+    /// Overlapping context of close matches ends up in one group.
+    pub fn groups(&self) -> Vec<Vec<&SearchOneLineContentResult>> {
+        let mut all = self.lines.iter().chain(self.context.iter()).collect::<Vec<_>>();
+        all.sort_by_key(|line| line.line_num);
+        all.into_iter().fold(vec![], |mut groups: Vec<Vec<_>>, line| {
+            match groups.last_mut() {
+                Some(group) if group.last().unwrap().line_num + 1 == line.line_num => {
+                    group.push(line)
+                }
+                _ => groups.push(vec![line]),
+            }
+            groups
+        })
+    }
 }
 
 impl SearchOneLineContentResult {
@@ -182,101 +724,642 @@ impl SearchOneLineContentResult {
     pub fn match_range(&self) -> &[(u32, u32)] {
         &self.match_range
     }
+
+    /// `match_range` as 1-based `[start, end)` character columns, what editors
+    /// count in a line holding non-ASCII text.
+    pub fn match_columns(&self) -> Vec<(u32, u32)> {
+        let column = |byte: u32| {
+            let before = self.content.get(..byte as usize).unwrap_or(&self.content);
+            before.chars().count() as u32 + 1
+        };
+        self.match_range
+            .iter()
+            .map(|(start, end)| (column(*start), column(*end)))
+            .collect()
+    }
+
+    pub fn is_context(&self) -> bool {
+        self.is_context
+    }
+
+    pub fn terms(&self) -> &[u32] {
+        &self.terms
+    }
+
+    pub fn absolute_offset(&self) -> u64 {
+        self.absolute_offset
+    }
+
+    pub fn line_end(&self) -> &str {
+        self.line_end
+    }
+
+    pub fn raw_content(&self) -> Option<&[u8]> {
+        self.raw_content.as_deref()
+    }
+
+    pub fn raw_match_range(&self) -> &[(u32, u32)] {
+        &self.raw_match_range
+    }
 }
 
 impl SearchEngine {
-    pub fn search(&self, pattern: &str) -> Result<SearchOneEngine> {
-        let n = self.global_data.ngram_len();
-        let hir = parse(pattern).map_err(|e| anyhow!("parse error: {}", e))?;
-        let tree = Self::ngram_from_hir(&hir, n);
-        let re = regex::Regex::new(pattern).map_err(|e| anyhow!("regex error: {}", e))?;
+    /// `query` is one pattern, or a `SearchQuery` with `SearchOptions::with_query`.
+    pub fn search_with(&self, query: &str, options: &SearchOptions) -> Result<SearchOneEngine> {
+        let query = match options.query() {
+            true => SearchQuery::try_from(query)?,
+            false => SearchQuery::pattern(query),
+        };
+        let has_filters = !options.path_filters().is_empty() || !query.path_filters().is_empty();
+        if has_filters && self.path_table.is_none() {
+            return Err(anyhow!("path filters need the path table of the index"));
+        }
+        let terms = query
+            .terms()
+            .iter()
+            .map(|term| match options.fixed_strings() {
+                true => self.search_fixed(term, options),
+                false => self.search_regex(term, options),
+            })
+            .collect::<Result<Vec<_>>>()?;
         Ok(SearchOneEngine {
             global_data: self.global_data.clone(),
-            tree: tree,
-            re: re,
+            terms,
+            expr: query.expr.clone(),
+            scan_limit: self.scan_limit,
+            multiline: options.multiline(),
+            before_context: options.before_context(),
+            after_context: options.after_context(),
+            max_count: options.max_count(),
+            path_filters: options
+                .path_filters()
+                .iter()
+                .cloned()
+                .chain(query.path_filters().iter().cloned())
+                .collect(),
+            path_table: self.path_table.clone(),
         })
     }
+
+    /// File names for results and path filters, read from `paths.data`.
+    pub fn with_path_table(mut self, path_table: PathTable) -> Self {
+        self.path_table = Some(Arc::new(path_table));
+        self
+    }
+
+    /// Refuse full scans of more than `limit` files, `None` for no limit.
+    pub fn with_scan_limit(mut self, limit: Option<usize>) -> Self {
+        self.scan_limit = limit;
+        self
+    }
 }
 
-impl SearchEngine {
-    fn ngram_from_hir(hir: &Hir, n: u8) -> NgramTree {
-        let kind = hir.kind();
-        match kind {
-            HirKind::Empty => NgramTree::ALL,
-            HirKind::Literal(lit) => {
-                let sub_tree = NgramIndexVec::from((lit.0.as_ref(), n))
-                    .0
-                    .into_iter()
-                    .map(|ngram| NgramTree::Gram(ngram))
-                    .collect::<Vec<_>>();
-                let len = sub_tree.len();
-                match len {
-                    0 => NgramTree::ALL,
-                    1 => sub_tree.into_iter().nth(0).unwrap(),
-                    _ => NgramTree::Concat(sub_tree),
+impl SearchOptions {
+    pub fn ignore_case(&self) -> bool {
+        self.ignore_case
+    }
+
+    pub fn with_ignore_case(mut self, ignore_case: bool) -> Self {
+        self.ignore_case = ignore_case;
+        self
+    }
+
+    pub fn fixed_strings(&self) -> bool {
+        self.fixed_strings
+    }
+
+    /// Take the pattern as a literal string, no metacharacter needs escaping.
+    pub fn with_fixed_strings(mut self, fixed_strings: bool) -> Self {
+        self.fixed_strings = fixed_strings;
+        self
+    }
+
+    /// Fixed strings are never parsed as a query.
+    pub fn query(&self) -> bool {
+        self.query && !self.fixed_strings
+    }
+
+    /// Parse path filters and `AND`, `OR` and `NOT` out of the pattern, see `SearchQuery`.
+    pub fn with_query(mut self, query: bool) -> Self {
+        self.query = query;
+        self
+    }
+
+    pub fn multiline(&self) -> bool {
+        self.multiline
+    }
+
+    /// Let matches cross line ends, ngrams then only filter whole files.
+    pub fn with_multiline(mut self, multiline: bool) -> Self {
+        self.multiline = multiline;
+        self
+    }
+
+    pub fn before_context(&self) -> u32 {
+        self.before_context
+    }
+
+    pub fn after_context(&self) -> u32 {
+        self.after_context
+    }
+
+    /// Lines to show before and after each matched line.
+    pub fn with_context(mut self, before: u32, after: u32) -> Self {
+        self.before_context = before;
+        self.after_context = after;
+        self
+    }
+
+    pub fn max_count(&self) -> Option<u32> {
+        self.max_count
+    }
+
+    /// Stop verifying a file after this many matched lines.
+    pub fn with_max_count(mut self, max_count: Option<u32>) -> Self {
+        self.max_count = max_count;
+        self
+    }
+
+    pub fn path_filters(&self) -> &[PathFilter] {
+        &self.path_filters
+    }
+
+    /// Filter applied on top of the ones written in the search term.
+    pub fn with_path_filter(mut self, filter: PathFilter) -> Self {
+        self.path_filters.push(filter);
+        self
+    }
+}
+
+impl PathFilter {
+    pub fn matches(&self, path: &str) -> bool {
+        self.re.is_match(path) != self.exclude
+    }
+
+    /// `path:`, `glob:` or `lang:` with a value, optionally negated by a `-`.
+    fn is_filter(token: &str) -> bool {
+        let token = token.strip_prefix('-').unwrap_or(token);
+        PATH_FILTER_KEYS
+            .iter()
+            .any(|key| token.len() > key.len() && token.starts_with(key))
+    }
+
+    /// Regex matching the whole name, or the trailing components of it.
+    fn glob_to_regex(glob: &str) -> String {
+        let mut re = String::from("(?:^|/)");
+        let mut chars = glob.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    // `**/` also matches no directory at all.
+                    match chars.peek() == Some(&'/') {
+                        true => {
+                            chars.next();
+                            re.push_str("(?:.*/)?");
+                        }
+                        false => re.push_str(".*"),
+                    }
                 }
+                '*' => re.push_str("[^/]*"),
+                '?' => re.push_str("[^/]"),
+                '[' => {
+                    re.push('[');
+                    if chars.peek() == Some(&'!') {
+                        chars.next();
+                        re.push('^');
+                    }
+                    for c in chars.by_ref() {
+                        match c {
+                            ']' => break,
+                            '\\' | '[' | '&' | '~' => {
+                                re.push('\\');
+                                re.push(c);
+                            }
+                            _ => re.push(c),
+                        }
+                    }
+                    re.push(']');
+                }
+                _ => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+            }
+        }
+        re.push('$');
+        re
+    }
+}
+
+impl SearchQuery {
+    pub fn terms(&self) -> &[String] {
+        &self.terms
+    }
+
+    pub fn path_filters(&self) -> &[PathFilter] {
+        &self.path_filters
+    }
+}
+
+impl Matcher {
+    fn match_range(&self, line: &[u8]) -> Vec<(u32, u32)> {
+        match self {
+            Matcher::Regex(re) => re
+                .find_iter(line)
+                .map(|m| (m.start() as u32, m.end() as u32))
+                .collect(),
+            Matcher::Literal(finder) => {
+                let len = finder.needle().len() as u32;
+                finder
+                    .find_iter(line)
+                    .map(|start| (start as u32, start as u32 + len))
+                    .collect()
+            }
+        }
+    }
+}
+
+impl SearchEngine {
+    fn search_regex(&self, pattern: &str, options: &SearchOptions) -> Result<SearchTerm> {
+        let shape = self.global_data.ngram_shape();
+        let ast = ast::parse::Parser::new()
+            .parse(pattern)
+            .map_err(|e| anyhow!("parse error: {}", e))?;
+        let hir = ParserBuilder::new()
+            .case_insensitive(options.ignore_case())
+            .build()
+            .parse(pattern)
+            .map_err(|e| anyhow!("parse error: {}", e))?;
+        // Folded ngrams only help when some part of the pattern ignores case.
+        let fold = self.global_data.case_fold()
+            && (options.ignore_case() || Self::has_case_insensitive_flag(&ast));
+        let tree = Self::ngram_from_hir(&hir, shape, fold);
+        let re = regex::bytes::RegexBuilder::new(pattern)
+            .case_insensitive(options.ignore_case())
+            .multi_line(options.multiline())
+            .build()
+            .map_err(|e| anyhow!("regex error: {}", e))?;
+        Ok(SearchTerm {
+            pattern: pattern.to_string(),
+            tree: tree.split_lines(options.multiline()).select(&self.global_data),
+            matcher: Matcher::Regex(re),
+        })
+    }
+
+    /// The literal is its own ngram source, no regex is parsed.
+    fn search_fixed(&self, literal: &str, options: &SearchOptions) -> Result<SearchTerm> {
+        let shape = self.global_data.ngram_shape();
+        let fold = self.global_data.case_fold() && options.ignore_case();
+        let tree = match options.ignore_case() && !fold {
+            // No folded ngrams to look up, expand the case variants like a regex does.
+            true => {
+                let hir = ParserBuilder::new()
+                    .case_insensitive(true)
+                    .build()
+                    .parse(&regex::escape(literal))
+                    .map_err(|e| anyhow!("parse error: {}", e))?;
+                Self::ngram_from_hir(&hir, shape, false)
+            }
+            false => {
+                let set = vec![Self::fold_literal(literal.as_bytes(), fold)];
+                NgramTree::from((set, shape, fold))
+            }
+        };
+        // Case insensitive substrings need Unicode case folding, leave that to an escaped regex.
+        let matcher = match options.ignore_case() {
+            true => Matcher::Regex(
+                regex::bytes::RegexBuilder::new(&regex::escape(literal))
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|e| anyhow!("regex error: {}", e))?,
+            ),
+            false => Matcher::Literal(Box::new(
+                memchr::memmem::Finder::new(literal.as_bytes()).into_owned(),
+            )),
+        };
+        Ok(SearchTerm {
+            pattern: literal.to_string(),
+            tree: tree.split_lines(options.multiline()).select(&self.global_data),
+            matcher,
+        })
+    }
+
+    /// Whether an inline `(?i)` turns case folding on anywhere in the pattern.
+    fn has_case_insensitive_flag(ast: &Ast) -> bool {
+        let on = |flags: &ast::Flags| flags.flag_state(ast::Flag::CaseInsensitive) == Some(true);
+        match ast {
+            Ast::Flags(set) => on(&set.flags),
+            Ast::Group(group) => {
+                matches!(&group.kind, ast::GroupKind::NonCapturing(flags) if on(flags))
+                    || Self::has_case_insensitive_flag(&group.ast)
             }
-            HirKind::Class(_) => NgramTree::ALL,
-            HirKind::Look(_) => NgramTree::ALL,
-            HirKind::Repetition(r) => match r.sub.kind() {
-                HirKind::Literal(lit) => {
-                    let lit = lit.0.as_ref();
-                    let lit = std::iter::repeat_n(lit, r.min as usize)
-                        .flatten()
-                        .cloned()
-                        .collect::<Vec<_>>();
-                    let sub_tree = NgramIndexVec::from((lit.as_slice(), n))
-                        .0
-                        .into_iter()
-                        .map(|ngram| NgramTree::Gram(ngram))
-                        .collect::<Vec<_>>();
-                    NgramTree::Concat(sub_tree)
+            Ast::Repetition(r) => Self::has_case_insensitive_flag(&r.ast),
+            Ast::Alternation(alt) => alt.asts.iter().any(Self::has_case_insensitive_flag),
+            Ast::Concat(concat) => concat.asts.iter().any(Self::has_case_insensitive_flag),
+            _ => false,
+        }
+    }
+
+    /// With `fold` the tree is made of folded ngrams, literals are lowercased to match them.
+    fn ngram_from_hir(hir: &Hir, shape: NgramShape, fold: bool) -> NgramTree {
+        if let Some(set) = Self::literal_set(hir, fold) {
+            return NgramTree::from((set, shape, fold));
+        }
+        match hir.kind() {
+            HirKind::Empty | HirKind::Literal(_) | HirKind::Class(_) | HirKind::Look(_) => {
+                NgramTree::ALL
+            }
+            // `?`, `*` and `{0,n}` may match nothing, so they can not filter.
+            HirKind::Repetition(r) if r.min == 0 => NgramTree::ALL,
+            HirKind::Repetition(r) => match Self::literal_set(&r.sub, fold) {
+                Some(set) => {
+                    let set = std::iter::repeat_n(set, r.min as usize)
+                        .try_fold(vec![vec![]], |acc, set| Self::cross(&acc, &set));
+                    match set {
+                        Some(set) => NgramTree::from((set, shape, fold)),
+                        None => SearchEngine::ngram_from_hir(r.sub.as_ref(), shape, fold),
+                    }
                 }
-                _ => NgramTree::ALL,
+                None => SearchEngine::ngram_from_hir(r.sub.as_ref(), shape, fold),
             },
-            HirKind::Capture(c) => SearchEngine::ngram_from_hir(c.sub.as_ref(), n),
+            HirKind::Capture(c) => SearchEngine::ngram_from_hir(c.sub.as_ref(), shape, fold),
+            // Adjacent children with a small literal set are joined into one
+            // set, so ngrams crossing their boundaries are used too.
             HirKind::Concat(hirs) => {
-                let sub_tree = hirs
-                    .iter()
-                    .map(|hir| SearchEngine::ngram_from_hir(hir, n))
-                    .collect::<Vec<_>>();
-                NgramTree::Concat(sub_tree)
+                let mut sub_tree = vec![];
+                let mut set = vec![vec![]];
+                hirs.iter().for_each(|hir| match Self::literal_set(hir, fold) {
+                    Some(next) => match Self::cross(&set, &next) {
+                        Some(joined) => set = joined,
+                        None => {
+                            sub_tree.push(NgramTree::from((std::mem::take(&mut set), shape, fold)));
+                            set = next;
+                        }
+                    },
+                    None => {
+                        sub_tree.push(NgramTree::from((std::mem::take(&mut set), shape, fold)));
+                        sub_tree.push(SearchEngine::ngram_from_hir(hir, shape, fold));
+                        set = vec![vec![]];
+                    }
+                });
+                sub_tree.push(NgramTree::from((set, shape, fold)));
+                sub_tree.retain(|tree| tree != &NgramTree::ALL);
+                match sub_tree.len() {
+                    0 => NgramTree::ALL,
+                    1 => sub_tree.pop().unwrap(),
+                    _ => NgramTree::Concat(sub_tree),
+                }
             }
             HirKind::Alternation(hirs) => {
                 let sub_tree = hirs
                     .iter()
-                    .map(|hir| SearchEngine::ngram_from_hir(hir, n))
+                    .map(|hir| SearchEngine::ngram_from_hir(hir, shape, fold))
                     .collect::<Vec<_>>();
                 NgramTree::Alternation(sub_tree)
             }
         }
     }
+
+    /// Every string `hir` can match, when there are at most `LITERAL_SET_BUDGET` of them.
+    /// With `fold` the strings are lowercased, so `[Ff]oo` is just `foo`.
+    fn literal_set(hir: &Hir, fold: bool) -> Option<Vec<Vec<u8>>> {
+        // A case insensitive class holds every case of its chars, fold it before the limit check.
+        let class_limit = match fold {
+            true => CLASS_EXPAND_LIMIT * 2,
+            false => CLASS_EXPAND_LIMIT,
+        };
+        match hir.kind() {
+            HirKind::Empty | HirKind::Look(_) => Some(vec![vec![]]),
+            HirKind::Literal(lit) => Some(vec![Self::fold_literal(&lit.0, fold)]),
+            HirKind::Class(Class::Unicode(class)) => {
+                let len = class
+                    .ranges()
+                    .iter()
+                    .map(|r| r.end() as usize - r.start() as usize + 1)
+                    .sum::<usize>();
+                (len <= class_limit)
+                    .then(|| {
+                        class
+                            .ranges()
+                            .iter()
+                            .flat_map(|r| r.start()..=r.end())
+                            .map(|c| Self::fold_literal(c.to_string().as_bytes(), fold))
+                            .collect()
+                    })
+                    .and_then(|set| Self::union(set, vec![]))
+                    .filter(|set| set.len() <= CLASS_EXPAND_LIMIT)
+            }
+            HirKind::Class(Class::Bytes(class)) => {
+                let len = class
+                    .ranges()
+                    .iter()
+                    .map(|r| r.end() as usize - r.start() as usize + 1)
+                    .sum::<usize>();
+                (len <= class_limit)
+                    .then(|| {
+                        class
+                            .ranges()
+                            .iter()
+                            .flat_map(|r| r.start()..=r.end())
+                            .map(|b| Self::fold_literal(&[b], fold))
+                            .collect()
+                    })
+                    .and_then(|set| Self::union(set, vec![]))
+                    .filter(|set| set.len() <= CLASS_EXPAND_LIMIT)
+            }
+            HirKind::Repetition(r) => {
+                let max = r.max?;
+                let sub = Self::literal_set(&r.sub, fold)?;
+                let mut power = std::iter::repeat_n(&sub, r.min as usize)
+                    .try_fold(vec![vec![]], |acc, set| Self::cross(&acc, set))?;
+                let mut set = power.clone();
+                for _ in r.min..max {
+                    power = Self::cross(&power, &sub)?;
+                    set = Self::union(set, power.clone())?;
+                }
+                Some(set)
+            }
+            HirKind::Capture(c) => Self::literal_set(&c.sub, fold),
+            HirKind::Concat(hirs) => hirs.iter().try_fold(vec![vec![]], |acc, hir| {
+                Self::cross(&acc, &Self::literal_set(hir, fold)?)
+            }),
+            HirKind::Alternation(hirs) => hirs.iter().try_fold(vec![], |acc, hir| {
+                Self::union(acc, Self::literal_set(hir, fold)?)
+            }),
+        }
+    }
+
+    fn fold_literal(bytes: &[u8], fold: bool) -> Vec<u8> {
+        match fold {
+            true => fold_case_bytes(bytes),
+            false => bytes.to_vec(),
+        }
+    }
+
+    fn cross(a: &[Vec<u8>], b: &[Vec<u8>]) -> Option<Vec<Vec<u8>>> {
+        (a.len() * b.len() <= LITERAL_SET_BUDGET).then(|| {
+            a.iter()
+                .flat_map(|a| b.iter().map(move |b| [a.as_slice(), b.as_slice()].concat()))
+                .collect()
+        })
+    }
+
+    fn union(mut a: Vec<Vec<u8>>, b: Vec<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+        a.extend(b);
+        a.sort();
+        a.dedup();
+        (a.len() <= LITERAL_SET_BUDGET).then_some(a)
+    }
 }
 
 impl NgramTree {
+    /// Lines are indexed one by one, so ngrams holding a `\n` are never in the
+    /// index. In multiline mode they are dropped instead of matching nothing.
+    fn split_lines(self, multiline: bool) -> Self {
+        match (multiline, self) {
+            (false, tree) => tree,
+            (true, Self::Gram(gram)) if gram.ngrams().contains(&b'\n') => Self::ALL,
+            (true, Self::Concat(sub)) => {
+                let mut sub = sub
+                    .into_iter()
+                    .map(|t| t.split_lines(true))
+                    .filter(|t| t != &Self::ALL)
+                    .collect::<Vec<_>>();
+                match sub.len() {
+                    0 => Self::ALL,
+                    1 => sub.pop().unwrap(),
+                    _ => Self::Concat(sub),
+                }
+            }
+            (true, Self::Alternation(sub)) => {
+                let sub = sub.into_iter().map(|t| t.split_lines(true)).collect::<Vec<_>>();
+                match sub.contains(&Self::ALL) {
+                    true => Self::ALL,
+                    false => Self::Alternation(sub),
+                }
+            }
+            (true, tree) => tree,
+        }
+    }
+
+    /// Keeps the most selective children of every `Concat`, rarest first. A
+    /// posting list is only fetched when the candidates it is expected to cut
+    /// save more file blobs than the list costs. Files are assumed to hold
+    /// ngrams independently, ngrams missing from the index cost nothing.
+    fn select(self, global_data: &GlobalData) -> Self {
+        match self {
+            Self::Concat(sub) => {
+                let total = global_data.header().file_count().max(1) as f64;
+                let mut sub = sub
+                    .into_iter()
+                    .map(|t| {
+                        let t = t.select(global_data);
+                        let (files, cost) = t.estimate(global_data);
+                        (files, cost, t)
+                    })
+                    .collect::<Vec<_>>();
+                sub.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1)));
+                let (_, mut kept) = sub.into_iter().fold(
+                    (total, vec![]),
+                    |(estimate, mut kept), (files, cost, t)| {
+                        let ratio = (files / total).min(1.0);
+                        match kept.is_empty() || cost <= BLOB_COST * estimate * (1.0 - ratio) {
+                            true => {
+                                kept.push(t);
+                                (estimate * ratio, kept)
+                            }
+                            false => (estimate, kept),
+                        }
+                    },
+                );
+                match kept.len() {
+                    1 => kept.pop().unwrap(),
+                    _ => Self::Concat(kept),
+                }
+            }
+            Self::Alternation(sub) => {
+                Self::Alternation(sub.into_iter().map(|t| t.select(global_data)).collect())
+            }
+            tree => tree,
+        }
+    }
+
+    /// Expected candidate files of the tree and the posting list entries fetched for it.
+    fn estimate(&self, global_data: &GlobalData) -> (f64, f64) {
+        let total = global_data.header().file_count().max(1) as f64;
+        match self {
+            Self::ALL => (total, 0.0),
+            Self::Gram(gram) => {
+                let files = global_data.document_frequency(gram).unwrap_or_default() as f64;
+                (files, files)
+            }
+            Self::Alternation(sub) => {
+                let (files, cost) = sub
+                    .iter()
+                    .map(|t| t.estimate(global_data))
+                    .fold((0.0, 0.0), |(files, cost), (f, c)| (files + f, cost + c));
+                (files.min(total), cost)
+            }
+            Self::Concat(sub) => sub.iter().map(|t| t.estimate(global_data)).fold(
+                (total, 0.0),
+                |(files, cost), (f, c)| (files * (f / total).min(1.0), cost + c),
+            ),
+        }
+    }
+
     fn ngrams(&self) -> NgramIndexVec {
         let ngrams = match self {
             Self::ALL => vec![],
             Self::Gram(ngram) => vec![ngram.clone()],
-            Self::Alternation(sub) | Self::Concat(sub) => sub
-                .iter()
-                .map(|t| t.ngrams().0)
-                .flatten()
-                .collect::<Vec<_>>(),
+            Self::Alternation(sub) | Self::Concat(sub) => {
+                sub.iter().flat_map(|t| t.ngrams().0).collect::<Vec<_>>()
+            }
         };
         NgramIndexVec::from(ngrams)
     }
 
+    /// The candidates of the tree like `files_lines`, with a plan node per tree node.
+    fn plan(
+        &self,
+        index_data: &SearchOneNgramResult,
+        global_data: &GlobalData,
+        bytes: &HashMap<NgramIndex, usize>,
+    ) -> (SearchOneFilesLinesStructResult, PlanNode) {
+        let (step, children) = match self {
+            Self::ALL => (PlanStep::All, vec![]),
+            Self::Gram(gram) => {
+                let step = PlanStep::Gram {
+                    ngram: String::from_utf8_lossy(gram.ngrams()).into_owned(),
+                    folded: gram.is_folded(),
+                    bytes: global_data
+                        .has_index(gram)
+                        .then(|| bytes.get(gram).copied().unwrap_or_default()),
+                };
+                (step, vec![])
+            }
+            Self::Concat(sub) | Self::Alternation(sub) => {
+                let step = match self {
+                    Self::Concat(_) => PlanStep::Concat,
+                    _ => PlanStep::Alternation,
+                };
+                let children = sub
+                    .iter()
+                    .map(|t| t.plan(index_data, global_data, bytes).1)
+                    .collect();
+                (step, children)
+            }
+        };
+        let result = self.files_lines(index_data);
+        let node = PlanNode::from((step, result.candidates(), children));
+        (result, node)
+    }
+
     fn files_lines(&self, index_data: &SearchOneNgramResult) -> SearchOneFilesLinesStructResult {
-        let ans = match self {
+        match self {
             Self::ALL => SearchOneFilesLinesStructResult {
                 enum_result: SearchOneFilesLinesEnumResult::ALL,
             },
             Self::Gram(e) => match index_data.ngram_to_data(e) {
                 Some(data) => SearchOneFilesLinesStructResult {
-                    enum_result: SearchOneFilesLinesEnumResult::FilesLines(
-                        data.files_lines().clone(),
-                    ),
+                    enum_result: SearchOneFilesLinesEnumResult::FilesLines(data.files_lines()),
                 },
                 _ => SearchOneFilesLinesStructResult {
                     enum_result: SearchOneFilesLinesEnumResult::FilesLines(FilesLinesIndex::from(
@@ -284,23 +1367,85 @@ impl NgramTree {
                     )),
                 },
             },
-            Self::Alternation(sub) => sub.iter().map(|t| t.files_lines(index_data)).fold(
+            // Gram children are merged straight from their encoded posting lists.
+            Self::Alternation(sub) => sub.iter().fold(
                 SearchOneFilesLinesStructResult {
                     enum_result: SearchOneFilesLinesEnumResult::FilesLines(FilesLinesIndex::from(
                         vec![],
                     )),
                 },
-                |ans, result| ans.alternation(result),
+                |ans, t| match (ans, t) {
+                    (
+                        SearchOneFilesLinesStructResult {
+                            enum_result: SearchOneFilesLinesEnumResult::FilesLines(a),
+                        },
+                        Self::Gram(e),
+                    ) => SearchOneFilesLinesStructResult {
+                        enum_result: SearchOneFilesLinesEnumResult::FilesLines(
+                            match index_data.ngram_to_data(e) {
+                                Some(data) => FilesLinesIndex::union_iter(a, data.iter()),
+                                None => a,
+                            },
+                        ),
+                    },
+                    (ans, t) => ans.alternation(t.files_lines(index_data)),
+                },
             ),
 
-            Self::Concat(sub) => sub.iter().map(|t| t.files_lines(index_data)).fold(
+            Self::Concat(sub) => sub.iter().fold(
                 SearchOneFilesLinesStructResult {
                     enum_result: SearchOneFilesLinesEnumResult::ALL,
                 },
-                |ans, result| ans.concat(result),
+                |ans, t| match (ans, t) {
+                    (
+                        SearchOneFilesLinesStructResult {
+                            enum_result: SearchOneFilesLinesEnumResult::FilesLines(a),
+                        },
+                        Self::Gram(e),
+                    ) => SearchOneFilesLinesStructResult {
+                        enum_result: SearchOneFilesLinesEnumResult::FilesLines(
+                            match index_data.ngram_to_data(e) {
+                                Some(data) => FilesLinesIndex::intersection_iter(a, data.iter()),
+                                None => FilesLinesIndex::from(vec![]),
+                            },
+                        ),
+                    },
+                    (ans, t) => ans.concat(t.files_lines(index_data)),
+                },
             ),
-        };
-        ans
+        }
+    }
+}
+
+/// Any of the strings of the set must appear, as folded ngrams when `fold`.
+impl From<(Vec<Vec<u8>>, NgramShape, bool)> for NgramTree {
+    fn from((set, shape, fold): (Vec<Vec<u8>>, NgramShape, bool)) -> Self {
+        let mut sub_tree = set
+            .iter()
+            .map(|lit| {
+                let grams = shape
+                    .query_ngrams(lit)
+                    .0
+                    .into_iter()
+                    .map(|gram| match fold {
+                        true => NgramTree::Gram(gram.into_folded()),
+                        false => NgramTree::Gram(gram),
+                    })
+                    .collect::<Vec<_>>();
+                match grams.len() {
+                    0 => NgramTree::ALL,
+                    1 => grams.into_iter().next().unwrap(),
+                    _ => NgramTree::Concat(grams),
+                }
+            })
+            .collect::<Vec<_>>();
+        sub_tree.dedup();
+        match sub_tree.len() {
+            0 => NgramTree::ALL,
+            _ if sub_tree.contains(&NgramTree::ALL) => NgramTree::ALL,
+            1 => sub_tree.pop().unwrap(),
+            _ => NgramTree::Alternation(sub_tree),
+        }
     }
 }
 
@@ -328,10 +1473,27 @@ impl SearchOneFilesLinesStructResult {
             } => Ok(files_lines_index
                 .files_lines()
                 .iter()
-                .map(|file_lines| file_lines.file_id().clone())
+                .map(|file_lines| *file_lines.file_id())
                 .collect()),
         }
     }
+    pub fn is_all(&self) -> bool {
+        matches!(self.enum_result, SearchOneFilesLinesEnumResult::ALL)
+    }
+    /// Candidate files and lines, `None` when every file is a candidate.
+    fn candidates(&self) -> Option<Candidates> {
+        match &self.enum_result {
+            SearchOneFilesLinesEnumResult::ALL => None,
+            SearchOneFilesLinesEnumResult::FilesLines(files_lines_index) => {
+                let files_lines = files_lines_index.files_lines();
+                let lines = files_lines
+                    .iter()
+                    .map(|file_lines| file_lines.lines_index().lines().len())
+                    .sum();
+                Some(Candidates::from((files_lines.len(), lines)))
+            }
+        }
+    }
     pub fn binary_search_file(&self, file_index: &FileIndex) -> Option<&LinesIndex> {
         match self {
             SearchOneFilesLinesStructResult {
@@ -380,6 +1542,46 @@ impl SearchOneFilesLinesStructResult {
         }
     }
 
+    /// Files in both `self` and `other`, with the lines of either.
+    fn both(self, other: Self) -> Self {
+        match (self, other) {
+            (
+                SearchOneFilesLinesStructResult {
+                    enum_result: SearchOneFilesLinesEnumResult::ALL,
+                },
+                r,
+            )
+            | (
+                r,
+                SearchOneFilesLinesStructResult {
+                    enum_result: SearchOneFilesLinesEnumResult::ALL,
+                },
+            ) => r,
+            (
+                SearchOneFilesLinesStructResult {
+                    enum_result: SearchOneFilesLinesEnumResult::FilesLines(a),
+                },
+                SearchOneFilesLinesStructResult {
+                    enum_result: SearchOneFilesLinesEnumResult::FilesLines(b),
+                },
+            ) => {
+                let files =
+                    FilesLinesIndex::intersection(a.clone().files_only(), b.clone().files_only());
+                let lines = FilesLinesIndex::union(a, b);
+                let dropped = FilesLinesIndex::difference(lines.clone().files_only(), files)
+                    .files_lines()
+                    .iter()
+                    .map(|file_lines| *file_lines.file_id())
+                    .collect::<HashSet<_>>();
+                SearchOneFilesLinesStructResult {
+                    enum_result: SearchOneFilesLinesEnumResult::FilesLines(
+                        lines.remove_files(&dropped),
+                    ),
+                }
+            }
+        }
+    }
+
     fn concat(self, other: Self) -> Self {
         match (self, other) {
             (
@@ -416,9 +1618,278 @@ impl SearchOneNgramResult {
     }
 }
 
+impl TryFrom<&str> for PathFilter {
+    type Error = anyhow::Error;
+
+    fn try_from(token: &str) -> Result<Self> {
+        let (exclude, filter) = match token.strip_prefix('-') {
+            Some(filter) => (true, filter),
+            None => (false, token),
+        };
+        let re = match filter.split_once(':') {
+            Some(("path", re)) => re.to_string(),
+            Some(("glob", glob)) => PathFilter::glob_to_regex(glob),
+            Some(("lang", lang)) => {
+                let extensions = LANG_EXTENSIONS
+                    .iter()
+                    .find(|(name, _)| *name == lang)
+                    .map(|(_, extensions)| extensions)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "unknown lang {}, known langs are {}",
+                            lang,
+                            LANG_EXTENSIONS
+                                .iter()
+                                .map(|(name, _)| *name)
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        )
+                    })?;
+                let extensions = extensions.iter().map(|ext| regex::escape(ext));
+                format!(r"\.(?:{})$", extensions.collect::<Vec<_>>().join("|"))
+            }
+            _ => return Err(anyhow!("not a path filter: {}", token)),
+        };
+        let re = regex::Regex::new(&re).map_err(|e| anyhow!("filter {} error: {}", token, e))?;
+        Ok(PathFilter { exclude, re })
+    }
+}
+
+impl TryFrom<&str> for SearchQuery {
+    type Error = anyhow::Error;
+
+    fn try_from(query: &str) -> Result<Self> {
+        let mut rest = query;
+        let mut path_filters = vec![];
+        loop {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            if !PathFilter::is_filter(&rest[..end]) {
+                break;
+            }
+            path_filters.push(PathFilter::try_from(&rest[..end])?);
+            rest = rest[end..].trim_start();
+        }
+        // An empty term would match every line of the filtered files.
+        if rest.is_empty() {
+            return Err(anyhow!("query has no pattern"));
+        }
+        let tokens = SearchQuery::tokenize(rest);
+        let (terms, expr) = match tokens.iter().any(|token| !matches!(token, QueryToken::Term(_))) {
+            true => {
+                let mut terms = vec![];
+                let mut tokens = tokens.into_iter().peekable();
+                let expr = QueryExpr::parse_or(&mut tokens, &mut terms)?;
+                if let Some(token) = tokens.next() {
+                    return Err(anyhow!("unexpected {} in query", token));
+                }
+                (terms, expr)
+            }
+            // Without operators the pattern is kept as is, spaces included.
+            false => (vec![rest.to_string()], QueryExpr::Term(0)),
+        };
+        Ok(SearchQuery {
+            terms,
+            expr,
+            path_filters,
+        })
+    }
+}
+
+impl SearchQuery {
+    /// `pattern` as the only term, without filters.
+    fn pattern(pattern: &str) -> Self {
+        SearchQuery {
+            terms: vec![pattern.to_string()],
+            expr: QueryExpr::Term(0),
+            path_filters: vec![],
+        }
+    }
+
+    /// Splits at the `AND`, `OR` and `NOT` words, the text between them is one term.
+    fn tokenize(query: &str) -> Vec<QueryToken> {
+        let mut tokens = vec![];
+        let mut term: Vec<&str> = vec![];
+        let flush = |term: &mut Vec<&str>, tokens: &mut Vec<QueryToken>| {
+            if !term.is_empty() {
+                tokens.push(QueryToken::Term(term.join(" ")));
+                term.clear();
+            }
+        };
+        query.split_whitespace().for_each(|word| {
+            let token = match word {
+                "AND" => QueryToken::And,
+                "OR" => QueryToken::Or,
+                "NOT" => QueryToken::Not,
+                _ => {
+                    term.push(word);
+                    return;
+                }
+            };
+            flush(&mut term, &mut tokens);
+            tokens.push(token);
+        });
+        flush(&mut term, &mut tokens);
+        tokens
+    }
+}
+
+impl std::fmt::Display for QueryToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryToken::And => write!(f, "AND"),
+            QueryToken::Or => write!(f, "OR"),
+            QueryToken::Not => write!(f, "NOT"),
+            QueryToken::Term(term) => write!(f, "`{}`", term),
+        }
+    }
+}
+
+impl QueryExpr {
+    fn parse_or(
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<QueryToken>>,
+        terms: &mut Vec<String>,
+    ) -> Result<Self> {
+        let mut exprs = vec![Self::parse_and(tokens, terms, "OR")?];
+        while tokens.next_if_eq(&QueryToken::Or).is_some() {
+            exprs.push(Self::parse_and(tokens, terms, "OR")?);
+        }
+        Ok(Self::flatten(exprs, QueryExpr::Or))
+    }
+
+    /// `NOT` right after a term is an implicit `AND`.
+    fn parse_and(
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<QueryToken>>,
+        terms: &mut Vec<String>,
+        after: &str,
+    ) -> Result<Self> {
+        let mut exprs = vec![Self::parse_not(tokens, terms, after)?];
+        loop {
+            match tokens.peek() {
+                Some(QueryToken::And) => {
+                    tokens.next();
+                    exprs.push(Self::parse_not(tokens, terms, "AND")?);
+                }
+                Some(QueryToken::Not) => exprs.push(Self::parse_not(tokens, terms, "NOT")?),
+                _ => break,
+            }
+        }
+        Ok(Self::flatten(exprs, QueryExpr::And))
+    }
+
+    fn parse_not(
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<QueryToken>>,
+        terms: &mut Vec<String>,
+        after: &str,
+    ) -> Result<Self> {
+        match tokens.next() {
+            Some(QueryToken::Not) => Ok(QueryExpr::Not(Box::new(Self::parse_not(
+                tokens, terms, "NOT",
+            )?))),
+            Some(QueryToken::Term(term)) => {
+                terms.push(term);
+                Ok(QueryExpr::Term(terms.len() - 1))
+            }
+            Some(token) => Err(anyhow!("missing term before {}", token)),
+            None => Err(anyhow!("missing term after {}", after)),
+        }
+    }
+
+    fn flatten(mut exprs: Vec<Self>, f: fn(Vec<Self>) -> Self) -> Self {
+        match exprs.len() {
+            1 => exprs.remove(0),
+            _ => f(exprs),
+        }
+    }
+
+    /// Files which may satisfy the query, from the candidates of every term.
+    /// A file without a term may still satisfy `NOT term`, so `NOT` keeps every file.
+    fn candidates(
+        &self,
+        terms: &[SearchOneFilesLinesStructResult],
+    ) -> SearchOneFilesLinesStructResult {
+        let all = SearchOneFilesLinesStructResult {
+            enum_result: SearchOneFilesLinesEnumResult::ALL,
+        };
+        match self {
+            QueryExpr::Term(i) => terms[*i].clone(),
+            QueryExpr::Or(exprs) => exprs
+                .iter()
+                .map(|expr| expr.candidates(terms))
+                .reduce(SearchOneFilesLinesStructResult::alternation)
+                .unwrap_or(all),
+            QueryExpr::And(exprs) => exprs
+                .iter()
+                .map(|expr| expr.candidates(terms))
+                .fold(all, SearchOneFilesLinesStructResult::both),
+            QueryExpr::Not(_) => all,
+        }
+    }
+
+    /// Whether a file satisfies the query, `hit[i]` tells if term `i` matched in it.
+    fn eval(&self, hit: &[bool]) -> bool {
+        match self {
+            QueryExpr::Term(i) => hit[*i],
+            QueryExpr::And(exprs) => exprs.iter().all(|expr| expr.eval(hit)),
+            QueryExpr::Or(exprs) => exprs.iter().any(|expr| expr.eval(hit)),
+            QueryExpr::Not(expr) => !expr.eval(hit),
+        }
+    }
+
+    /// Terms whose lines are reported, those under a `NOT` only exclude files.
+    fn positive_terms(&self, len: usize) -> Vec<bool> {
+        let mut positive = vec![false; len];
+        self.mark_positive(true, &mut positive);
+        positive
+    }
+
+    fn mark_positive(&self, positive: bool, marks: &mut [bool]) {
+        match self {
+            QueryExpr::Term(i) => marks[*i] |= positive,
+            QueryExpr::And(exprs) | QueryExpr::Or(exprs) => {
+                exprs.iter().for_each(|expr| expr.mark_positive(positive, marks))
+            }
+            QueryExpr::Not(expr) => expr.mark_positive(!positive, marks),
+        }
+    }
+}
+
+impl SearchOneQueryResult {
+    pub fn is_all(&self) -> bool {
+        self.files.is_all()
+    }
+    pub fn files(&self) -> Result<Vec<FileIndex>> {
+        self.files.files()
+    }
+}
+
+impl From<SearchOneFilesLinesStructResult> for SearchOneQueryResult {
+    fn from(files: SearchOneFilesLinesStructResult) -> Self {
+        SearchOneQueryResult {
+            terms: vec![files.clone()],
+            files,
+        }
+    }
+}
+
+/// Sorts `ranges` and joins the overlapping ones.
+fn merge_ranges(mut ranges: Vec<(u32, u32)>) -> Vec<(u32, u32)> {
+    ranges.sort_unstable();
+    ranges.into_iter().fold(vec![], |mut merged: Vec<(u32, u32)>, (start, end)| {
+        match merged.last_mut() {
+            Some(last) if start < last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+        merged
+    })
+}
+
 impl From<GlobalData> for SearchEngine {
     fn from(global_data: GlobalData) -> Self {
-        Self { global_data }
+        Self {
+            global_data,
+            scan_limit: None,
+            path_table: None,
+        }
     }
 }
 
@@ -443,8 +1914,18 @@ impl From<(NgramIndex, NgramData)> for NgramIndexData {
 
 #[cfg(test)]
 mod tests {
-    use super::SearchOneFilesLinesStructResult;
-    use crate::ngram::index::{FileIndex, FileLinesIndex, FilesLinesIndex, LineIndex, LinesIndex};
+    use std::collections::HashMap;
+
+    use super::{
+        NgramTree, PathFilter, SearchEngine, SearchOneFilesLinesStructResult, SearchOneQueryResult,
+        SearchOptions, SearchQuery,
+    };
+    use crate::ngram::data::{GlobalData, IndexHeader, NgramData, PathTable};
+    use crate::ngram::encoding::TextEncoding;
+    use crate::ngram::index::{
+        FileIndex, FileLinesIndex, FilesLinesIndex, LineIndex, LinesIndex, NgramIndex, NgramShape,
+    };
+    use crate::ngram::rank::DefinitionLines;
     use crate::ngram::search::SearchOneFilesLinesEnumResult;
 
     fn files_lines(entries: &[(u32, &[u32])]) -> FilesLinesIndex {
@@ -461,6 +1942,15 @@ mod tests {
         FilesLinesIndex::from(files)
     }
 
+    /// A `FileData` blob of `lines`, all ending in `\n`.
+    fn file_blob<T: serde::Serialize>(
+        name: &str,
+        lines: Vec<T>,
+        encoding: TextEncoding,
+    ) -> Vec<u8> {
+        postcard::to_stdvec(&(name, lines, encoding, Vec::<u32>::new())).unwrap()
+    }
+
     fn flatten(index: &FilesLinesIndex) -> Vec<(u32, Vec<u32>)> {
         index
             .files_lines()
@@ -537,4 +2027,467 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn short_pattern_falls_back_to_full_scan() {
+        let mut global = GlobalData::from((IndexHeader::from((3, 0)), HashMap::new()));
+        global.set_files([0, 1, 4].map(FileIndex::from));
+        let engine = SearchEngine::from(global.clone());
+        let one = engine.search_with("ab", &SearchOptions::default()).unwrap();
+        assert!(one.ngrams().0.is_empty());
+        let result = one.files_lines(one.ngrams(), vec![]).unwrap();
+        assert!(result.is_all());
+        let files = one.files(&result).unwrap();
+        assert_eq!(files, vec![0, 1, 4].into_iter().map(FileIndex::from).collect::<Vec<_>>());
+        let batch = one.files_batch(&result, 1, 5).unwrap();
+        assert_eq!(batch, vec![FileIndex::from(1), FileIndex::from(4)]);
+
+        let engine = SearchEngine::from(global).with_scan_limit(Some(2));
+        let one = engine.search_with("ab", &SearchOptions::default()).unwrap();
+        let result = one.files_lines(one.ngrams(), vec![]).unwrap();
+        assert!(one.files(&result).is_err());
+    }
+
+    fn tree(pattern: &str) -> NgramTree {
+        let hir = regex_syntax::parse(pattern).unwrap();
+        SearchEngine::ngram_from_hir(&hir, NgramShape::Fixed(3), false)
+    }
+
+    fn has_ngram(tree: &NgramTree, ngram: &str) -> bool {
+        tree.ngrams()
+            .0
+            .iter()
+            .any(|index| index.ngrams() == ngram.as_bytes())
+    }
+
+    #[test]
+    fn small_class_expands_into_alternation() {
+        let get = tree("get[A-Z]ata");
+        assert!(matches!(get, NgramTree::Alternation(ref sub) if sub.len() == 26));
+        assert!(has_ngram(&get, "etA") && has_ngram(&get, "Zat"));
+
+        let foo = tree("[Ff]oo_bar");
+        assert!(has_ngram(&foo, "Foo") && has_ngram(&foo, "foo"));
+        assert!(has_ngram(&foo, "_ba"));
+
+        assert_eq!(tree("[a-z]+_bar"), tree("_bar"));
+        assert_eq!(tree("x[^a]y"), NgramTree::ALL);
+    }
+
+    #[test]
+    fn optional_repetition_is_expanded() {
+        let color = tree("colou?r");
+        assert!(matches!(color, NgramTree::Alternation(ref sub) if sub.len() == 2));
+        assert!(has_ngram(&color, "lor") && has_ngram(&color, "lou"));
+
+        assert_eq!(tree("ab(cd)?"), NgramTree::ALL);
+        assert_eq!(tree("(abc)*"), NgramTree::ALL);
+        assert!(has_ngram(&tree("(abc)+x"), "abc"));
+    }
+
+    #[test]
+    fn ignore_case_uses_folded_ngrams() {
+        let mut global = GlobalData::from((IndexHeader::from((3, 0)), HashMap::new()));
+        global.set_case_fold(true);
+        let engine = SearchEngine::from(global.clone());
+        let ignore_case = SearchOptions::default().with_ignore_case(true);
+
+        let one = engine.search_with("FooBar", &ignore_case).unwrap();
+        let ngrams = one.terms[0].tree.ngrams().0;
+        assert!(ngrams.iter().all(|ngram| ngram.is_folded()));
+        assert!(has_ngram(&one.terms[0].tree, "foo") && has_ngram(&one.terms[0].tree, "oba"));
+        assert!(!one.terms[0].matcher.match_range(b"foobar").is_empty());
+
+        let inline = engine.search_with("x(?i:FooBar)", &SearchOptions::default()).unwrap();
+        assert!(has_ngram(&inline.terms[0].tree, "xfo"));
+        assert!(inline.terms[0].tree.ngrams().0.iter().all(|ngram| ngram.is_folded()));
+
+        let exact = engine.search_with("FooBar", &SearchOptions::default()).unwrap();
+        assert!(exact.terms[0].tree.ngrams().0.iter().all(|ngram| !ngram.is_folded()));
+        assert!(exact.terms[0].matcher.match_range(b"foobar").is_empty());
+
+        // Without folded ngrams the case variants are expanded as far as the budget allows.
+        global.set_case_fold(false);
+        let engine = SearchEngine::from(global);
+        let one = engine.search_with("Foo", &ignore_case).unwrap();
+        assert!(has_ngram(&one.terms[0].tree, "FOO") && has_ngram(&one.terms[0].tree, "foo"));
+    }
+
+    #[test]
+    fn fixed_strings_skip_regex_parsing() {
+        let global = GlobalData::from((IndexHeader::from((3, 0)), HashMap::new()));
+        let engine = SearchEngine::from(global);
+        let fixed = SearchOptions::default().with_fixed_strings(true);
+
+        assert!(engine.search_with("a[i].b(", &SearchOptions::default()).is_err());
+        let one = engine.search_with("a[i].b(", &fixed).unwrap();
+        assert!(has_ngram(&one.terms[0].tree, "[i]") && has_ngram(&one.terms[0].tree, ".b("));
+        assert_eq!(one.terms[0].matcher.match_range(b"x = a[i].b(a[i].b(1));"), vec![(4, 11), (11, 18)]);
+        assert!(one.terms[0].matcher.match_range(b"a[i]xb(").is_empty());
+
+        let one = engine
+            .search_with("std::vector<int>*", &fixed.clone().with_ignore_case(true))
+            .unwrap();
+        assert_eq!(one.terms[0].matcher.match_range(b"STD::Vector<int>*"), vec![(0, 17)]);
+
+        // A non-folded index has no folded ngrams, the case variants are looked up instead.
+        let one = engine.search_with("Foo", &fixed.clone().with_ignore_case(true)).unwrap();
+        assert!(one.terms[0].tree.ngrams().0.iter().all(|ngram| !ngram.is_folded()));
+        assert!(has_ngram(&one.terms[0].tree, "FOO") && has_ngram(&one.terms[0].tree, "foo"));
+        assert_eq!(one.terms[0].matcher.match_range(b"fOO"), vec![(0, 3)]);
+    }
+
+    #[test]
+    fn multiline_matches_span_lines() {
+        let mut global = GlobalData::from((IndexHeader::from((3, 0)), HashMap::new()));
+        global.set_files([FileIndex::from(0)]);
+        let engine = SearchEngine::from(global);
+        let multiline = SearchOptions::default().with_multiline(true);
+
+        let one = engine.search_with(r"if \(.*\)\n\s*return", &multiline).unwrap();
+        assert!(has_ngram(&one.terms[0].tree, "if ") && has_ngram(&one.terms[0].tree, "urn"));
+        assert!(!one.terms[0].tree.ngrams().0.iter().any(|gram| gram.ngrams().contains(&b'\n')));
+
+        let lines = vec!["int f() {", "  if (x)", "    return 1;", "}"];
+        let file = file_blob("a.c", lines, TextEncoding::Utf8);
+        let result = SearchOneQueryResult::from(SearchOneFilesLinesStructResult {
+            enum_result: SearchOneFilesLinesEnumResult::FilesLines(files_lines(&[(0, &[0])])),
+        });
+        let matched = one.file_lines_match(FileIndex::from(0), file, &result).unwrap();
+        let ranges = matched
+            .lines()
+            .iter()
+            .map(|line| (line.line_num(), line.match_range().to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, vec![(2, vec![(2, 8)]), (3, vec![(0, 10)])]);
+
+        let one = engine.search_with("ab\ncd", &multiline).unwrap();
+        assert_eq!(one.terms[0].tree, NgramTree::ALL);
+
+        let one = engine.search_with("x*", &multiline).unwrap();
+        let empty = file_blob("e.c", Vec::<&str>::new(), TextEncoding::Utf8);
+        let result = SearchOneQueryResult::from(SearchOneFilesLinesStructResult {
+            enum_result: SearchOneFilesLinesEnumResult::ALL,
+        });
+        let matched = one.file_lines_match(FileIndex::from(0), empty, &result).unwrap();
+        assert!(matched.lines().is_empty());
+    }
+
+    #[test]
+    fn match_columns_count_characters() {
+        let global = GlobalData::from((IndexHeader::from((3, 0)), HashMap::new()));
+        let engine = SearchEngine::from(global);
+        let one = engine.search_with("ü+", &SearchOptions::default()).unwrap();
+        let result = SearchOneQueryResult::from(SearchOneFilesLinesStructResult {
+            enum_result: SearchOneFilesLinesEnumResult::ALL,
+        });
+        let lines = vec!["é = \"üü\"; // ü"];
+        let file = file_blob("a.c", lines, TextEncoding::Utf8);
+        let matched = one.file_lines_match(FileIndex::from(0), file, &result).unwrap();
+        let line = &matched.lines()[0];
+        assert_eq!(line.match_range(), &[(6, 10), (16, 18)]);
+        assert_eq!(line.match_columns(), vec![(6, 8), (14, 15)]);
+
+        let one = engine.search_with("x*", &SearchOptions::default()).unwrap();
+        let file = file_blob("a.c", vec!["café"], TextEncoding::Utf8);
+        let matched = one.file_lines_match(FileIndex::from(0), file, &result).unwrap();
+        let line = &matched.lines()[0];
+        assert_eq!(line.match_range(), &[(0, 0), (1, 1), (2, 2), (3, 3), (5, 5)]);
+        assert_eq!(line.match_columns().last(), Some(&(5, 5)));
+    }
+
+    #[test]
+    fn legacy_encoded_lines_match_on_bytes() {
+        let global = GlobalData::from((IndexHeader::from((3, 0)), HashMap::new()));
+        let engine = SearchEngine::from(global);
+        let one = engine.search_with("m;", &SearchOptions::default()).unwrap();
+        let result = SearchOneQueryResult::from(SearchOneFilesLinesStructResult {
+            enum_result: SearchOneFilesLinesEnumResult::ALL,
+        });
+        let lines = vec![b"/* caf\xe9 */".to_vec(), b"\xe9 = m;".to_vec()];
+        let file = file_blob("a.c", lines, TextEncoding::Latin1);
+        let matched = one.file_lines_match(FileIndex::from(0), file, &result).unwrap();
+        let line = &matched.lines()[0];
+        assert_eq!(line.content(), "é = m;");
+        assert_eq!(line.match_range(), &[(5, 7)]);
+
+        let one = engine.search_with("(?i)CAF", &SearchOptions::default()).unwrap();
+        let lines = vec![b"char *s = \"\x93\xfa\"; /* caf */".to_vec()];
+        let file = file_blob("a.c", lines, TextEncoding::ShiftJis);
+        let matched = one.file_lines_match(FileIndex::from(0), file, &result).unwrap();
+        assert_eq!(matched.lines()[0].content(), "char *s = \"日\"; /* caf */");
+    }
+
+    #[test]
+    fn context_lines_merge_into_groups() {
+        let global = GlobalData::from((IndexHeader::from((3, 0)), HashMap::new()));
+        let engine = SearchEngine::from(global);
+        let options = SearchOptions::default().with_context(1, 1);
+        let one = engine.search_with("hit", &options).unwrap();
+
+        let lines = vec!["a", "b", "hit", "c", "hit", "d", "e", "f", "hit"];
+        let file = file_blob("a.c", lines, TextEncoding::Utf8);
+        let result = SearchOneQueryResult::from(SearchOneFilesLinesStructResult {
+            enum_result: SearchOneFilesLinesEnumResult::FilesLines(files_lines(&[(0, &[2, 4, 8])])),
+        });
+        let matched = one.file_lines_match(FileIndex::from(0), file, &result).unwrap();
+        assert_eq!(matched.lines().len(), 3);
+        let groups = matched
+            .groups()
+            .iter()
+            .map(|group| {
+                group
+                    .iter()
+                    .map(|line| (line.line_num(), line.is_context()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            groups,
+            vec![
+                vec![(2, true), (3, false), (4, true), (5, false), (6, true)],
+                vec![(8, true), (9, false)],
+            ]
+        );
+    }
+
+    #[test]
+    fn max_count_stops_per_file() {
+        let global = GlobalData::from((IndexHeader::from((3, 0)), HashMap::new()));
+        let engine = SearchEngine::from(global);
+        let options = SearchOptions::default().with_max_count(Some(2)).with_query(true);
+        let one = engine.search_with("hit OR miss", &options).unwrap();
+        let all = SearchOneFilesLinesStructResult {
+            enum_result: SearchOneFilesLinesEnumResult::ALL,
+        };
+        let result = SearchOneQueryResult {
+            files: all.clone(),
+            terms: vec![all.clone(), all],
+        };
+        let file = |lines: Vec<&str>| file_blob("a.c", lines, TextEncoding::Utf8);
+        let lines = vec!["hit", "x", "miss", "hit", "hit"];
+
+        let matched = one
+            .file_lines_match(FileIndex::from(0), file(lines.clone()), &result)
+            .unwrap();
+        let line_nums = matched.lines().iter().map(|line| line.line_num()).collect::<Vec<_>>();
+        assert_eq!(line_nums, vec![1, 3]);
+        let count = one.file_match_count(FileIndex::from(0), file(lines.clone()), &result);
+        assert_eq!(count.unwrap(), 2);
+        assert!(one.file_is_match(FileIndex::from(0), file(lines), &result).unwrap());
+        let none = vec!["x", "y"];
+        assert!(!one.file_is_match(FileIndex::from(0), file(none.clone()), &result).unwrap());
+        assert_eq!(one.file_match_count(FileIndex::from(0), file(none), &result).unwrap(), 0);
+    }
+
+    #[test]
+    fn query_splits_path_filters() {
+        let query = SearchQuery::try_from("path:src/ -path:test lang:cpp foo  bar").unwrap();
+        assert_eq!(query.terms(), ["foo  bar"]);
+        assert_eq!(query.path_filters().len(), 3);
+        assert!(!query.path_filters()[1].matches("/repo/test/a.cc"));
+        let keep = |path: &str| query.path_filters().iter().all(|filter| filter.matches(path));
+        assert!(keep("/repo/src/net/socket.cc"));
+        assert!(!keep("/repo/src/test/socket.cc"));
+        assert!(!keep("/repo/src/net/socket.rs"));
+
+        assert_eq!(SearchQuery::try_from(" path:x").unwrap().terms(), [" path:x"]);
+        assert!(SearchQuery::try_from("lang:cobol foo").is_err());
+        assert!(SearchQuery::try_from("path:src/").is_err());
+        assert!(SearchQuery::try_from("path:src/ lang:cpp ").is_err());
+
+        let glob = |glob: &str, path: &str| PathFilter::try_from(glob).unwrap().matches(path);
+        assert!(glob("glob:*.h", "/repo/include/a.h"));
+        assert!(!glob("glob:*.h", "/repo/include/a.hpp"));
+        assert!(glob("glob:src/net/**", "/repo/src/net/tcp/a.cc"));
+        assert!(glob("glob:src/**/a.cc", "/repo/src/a.cc"));
+        assert!(!glob("-glob:third_party/**", "/repo/third_party/x/y.c"));
+        assert!(glob("glob:[a-c]?.c", "/repo/b1.c"));
+    }
+
+    #[test]
+    fn query_parses_boolean_operators() {
+        use super::QueryExpr::{And, Not, Or, Term};
+        let query = SearchQuery::try_from("lang:cpp Mutex AND unlock NOT lock_guard").unwrap();
+        assert_eq!(query.terms(), ["Mutex", "unlock", "lock_guard"]);
+        assert_eq!(
+            query.expr,
+            And(vec![Term(0), Term(1), Not(Box::new(Term(2)))])
+        );
+        let query = SearchQuery::try_from("a b OR c AND NOT d").unwrap();
+        assert_eq!(query.terms(), ["a b", "c", "d"]);
+        assert_eq!(
+            query.expr,
+            Or(vec![Term(0), And(vec![Term(1), Not(Box::new(Term(2)))])])
+        );
+        assert_eq!(query.expr.positive_terms(3), vec![true, true, false]);
+
+        assert!(SearchQuery::try_from("AND foo").is_err());
+        assert!(SearchQuery::try_from("foo OR").is_err());
+        assert!(SearchQuery::try_from("foo AND OR bar").is_err());
+    }
+
+    #[test]
+    fn select_fetches_only_selective_ngrams() {
+        let indexs = [("abc", 5), ("bcd", 900), ("cde", 900), ("def", 3)]
+            .map(|(gram, files)| (NgramIndex::from(gram.as_bytes()), files));
+        let mut global = GlobalData::from((IndexHeader::from((3, 0)), HashMap::from(indexs)));
+        global.set_files((0..1000).map(FileIndex::from));
+        let engine = SearchEngine::from(global);
+        let one = engine.search_with("abcdef", &SearchOptions::default()).unwrap();
+        let ngrams = one.ngrams().0.iter().map(|ngram| ngram.ngrams().to_vec()).collect::<Vec<_>>();
+        assert_eq!(ngrams, vec![b"abc".to_vec(), b"def".to_vec()]);
+        // Each branch of an alternation keeps its own selective ngrams.
+        let one = engine.search_with("abcdef|bcde", &SearchOptions::default()).unwrap();
+        assert_eq!(one.ngrams().0.len(), 4);
+    }
+
+    #[test]
+    fn explain_counts_candidates_per_node() {
+        let indexs = [("abc", 2), ("bcd", 1)].map(|(gram, files)| (NgramIndex::from(gram.as_bytes()), files));
+        let mut global = GlobalData::from((IndexHeader::from((3, 0)), HashMap::from(indexs)));
+        global.set_files((0..100).map(FileIndex::from));
+        let engine = SearchEngine::from(global);
+        let one = engine.search_with("abcd", &SearchOptions::default()).unwrap();
+        let ngrams = one.ngrams();
+        let datas = ngrams
+            .0
+            .iter()
+            .map(|ngram| {
+                let entries: &[(u32, &[u32])] = match ngram.ngrams() {
+                    b"abc" => &[(0, &[0, 1]), (1, &[0])],
+                    _ => &[(0, &[1])],
+                };
+                NgramData::from(files_lines(entries)).to_data().unwrap()
+            })
+            .collect::<Vec<_>>();
+        let plan = one.explain(ngrams, datas).unwrap().to_string();
+        let lines = plan.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "term 1: abcd");
+        assert_eq!(lines[1], "  concat: 1 files, 1 lines");
+        // The rarest ngram comes first.
+        assert!(lines[2].starts_with("    gram \"bcd\" posting "));
+        assert!(lines[2].ends_with(": 1 files, 1 lines"));
+        assert!(lines[3].ends_with(": 2 files, 3 lines"));
+        assert_eq!(lines[4..], ["query: 1 files, 1 lines", "fetch 1 of 100 file blobs"]);
+
+        let one = engine.search_with("a.c", &SearchOptions::default()).unwrap();
+        let plan = one.explain(one.ngrams(), vec![]).unwrap().to_string();
+        assert!(plan.ends_with("  all: all files\nquery: all files\nfetch 100 of 100 file blobs"));
+    }
+
+    #[test]
+    fn boolean_query_matches_per_file() {
+        let global = GlobalData::from((IndexHeader::from((3, 0)), HashMap::new()));
+        let engine = SearchEngine::from(global);
+        let query = SearchOptions::default().with_query(true);
+        let one = engine.search_with("Mutex AND unlock NOT lock_guard", &query).unwrap();
+        let terms = vec![
+            SearchOneFilesLinesStructResult {
+                enum_result: SearchOneFilesLinesEnumResult::FilesLines(files_lines(&[
+                    (0, &[0]),
+                    (1, &[0]),
+                    (2, &[1]),
+                ])),
+            },
+            SearchOneFilesLinesStructResult {
+                enum_result: SearchOneFilesLinesEnumResult::FilesLines(files_lines(&[
+                    (0, &[2]),
+                    (2, &[2]),
+                ])),
+            },
+            SearchOneFilesLinesStructResult {
+                enum_result: SearchOneFilesLinesEnumResult::FilesLines(files_lines(&[(2, &[0])])),
+            },
+        ];
+        let result = SearchOneQueryResult {
+            files: one.expr.candidates(&terms),
+            terms,
+        };
+        // File 1 has no `unlock` candidate line, so it is never fetched.
+        assert_eq!(
+            result.files().unwrap(),
+            vec![FileIndex::from(0), FileIndex::from(2)]
+        );
+
+        let lines = vec!["Mutex m;", "int x;", "m.unlock();"];
+        let file = file_blob("a.c", lines, TextEncoding::Utf8);
+        let matched = one.file_lines_match(FileIndex::from(0), file, &result).unwrap();
+        let hits = matched
+            .lines()
+            .iter()
+            .map(|line| (line.line_num(), line.terms().to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(hits, vec![(1, vec![0]), (3, vec![1])]);
+
+        let lines = vec!["std::lock_guard g(m);", "Mutex m;", "m.unlock();"];
+        let file = file_blob("b.c", lines, TextEncoding::Utf8);
+        let matched = one.file_lines_match(FileIndex::from(2), file, &result).unwrap();
+        assert!(matched.is_empty());
+    }
+
+    #[test]
+    fn query_syntax_is_opt_in() {
+        let global = GlobalData::from((IndexHeader::from((3, 0)), HashMap::new()));
+        let engine = SearchEngine::from(global);
+        let query = SearchOptions::default().with_query(true);
+
+        let fixed = query.clone().with_fixed_strings(true);
+        let one = engine.search_with("path:x foo NOT NULL", &fixed).unwrap();
+        assert_eq!(one.terms.len(), 1);
+        assert_eq!(one.terms[0].matcher.match_range(b"path:x foo NOT NULL;"), vec![(0, 19)]);
+
+        let one = engine.search_with("foo NOT", &SearchOptions::default()).unwrap();
+        assert_eq!(one.terms.len(), 1);
+        assert_eq!(one.terms[0].matcher.match_range(b"if (foo NOT bar)"), vec![(4, 11)]);
+        assert!(engine.search_with("foo NOT", &query).is_err());
+    }
+
+    #[test]
+    fn path_filters_apply_before_fetching_files() {
+        let mut global = GlobalData::from((IndexHeader::from((3, 0)), HashMap::new()));
+        let files = vec![
+            (FileIndex::from(0), "/repo/src/a.cc".to_string()),
+            (FileIndex::from(1), "/repo/test/b.cc".to_string()),
+            (FileIndex::from(2), "/repo/src/c.rs".to_string()),
+        ];
+        global.set_files(files.iter().map(|(file_index, _)| *file_index));
+        let query = SearchOptions::default().with_query(true);
+        assert!(SearchEngine::from(global.clone()).search_with("-path:test ab", &query).is_err());
+        let engine = SearchEngine::from(global).with_path_table(PathTable::from(files));
+        let one = engine.search_with("-path:test lang:cpp ab", &query).unwrap();
+        let result = one.files_lines(one.ngrams(), vec![]).unwrap();
+        assert_eq!(one.files(&result).unwrap(), vec![FileIndex::from(0)]);
+
+        let options = SearchOptions::default()
+            .with_path_filter(PathFilter::try_from("glob:*.rs").unwrap());
+        let one = engine.search_with("ab", &options).unwrap();
+        let result = one.files_lines(one.ngrams(), vec![]).unwrap();
+        assert_eq!(one.files_batch(&result, 0, 10).unwrap(), vec![FileIndex::from(2)]);
+        assert_eq!(one.path(&FileIndex::from(1)).as_deref(), Some("/repo/test/b.cc"));
+    }
+
+    #[test]
+    fn ranked_pages_put_test_files_last() {
+        let mut global = GlobalData::from((IndexHeader::from((3, 0)), HashMap::new()));
+        let files = vec![
+            (FileIndex::from(0), "/repo/test/a.cc".to_string()),
+            (FileIndex::from(1), "/repo/src/deep/b.cc".to_string()),
+            (FileIndex::from(2), "/repo/src/c.cc".to_string()),
+        ];
+        global.set_files(files.iter().map(|(file_index, _)| *file_index));
+        let engine = SearchEngine::from(global).with_path_table(PathTable::from(files));
+        let one = engine.search_with("ab", &SearchOptions::default()).unwrap();
+        let result = one.files_lines(one.ngrams(), vec![]).unwrap();
+        let definitions = DefinitionLines::default();
+        assert_eq!(
+            one.ranked_files(&result, &definitions).unwrap(),
+            vec![FileIndex::from(2), FileIndex::from(1), FileIndex::from(0)]
+        );
+        assert_eq!(
+            one.ranked_page(&result, &definitions, 1, 2).unwrap(),
+            vec![FileIndex::from(0)]
+        );
+        assert!(one.ranked_page(&result, &definitions, 2, 2).unwrap().is_empty());
+    }
 }
@@ -1,7 +1,8 @@
 use bincode::{self, Decode, Encode};
 use crate::data::FromToData;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Decode, Encode, Clone, Copy)]
+#[derive(Debug, Decode, Encode, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub struct Range {
     pub start: Offset,
     pub len: u32,
@@ -22,6 +23,9 @@ impl Range {
     pub fn new(start: Offset, len: u32) -> Self {
         Range { start, len }
     }
+    pub fn end(&self) -> Offset {
+        self.start + self.len as Offset
+    }
 }
 
 
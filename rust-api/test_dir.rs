@@ -0,0 +1,30 @@
+use std::path::{Path, PathBuf};
+
+/// Scratch directory of a test below the system temp dir, keyed by the test
+/// name and the process id. It starts empty and is removed on drop, also when
+/// the test panics.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl From<&str> for TempDir {
+    fn from(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("igrep-{}-{}", name, std::process::id()));
+        // Leftovers of an earlier run that got the same pid.
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
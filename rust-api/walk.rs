@@ -0,0 +1,128 @@
+use anyhow::{Result, anyhow};
+use ignore::{DirEntry, WalkBuilder};
+use log::warn;
+use std::path::{Path, PathBuf};
+
+/// Per directory ignore file of igrep, same syntax as `.gitignore`.
+const IGREP_IGNORE: &str = ".igrepignore";
+/// Version control directories, hidden ones are skipped anyway.
+const VCS_DIRS: &[&str] = &[".git", ".hg", ".svn", ".bzr", "_darcs", "CVS"];
+
+/// Lists the files below some root directories, honoring `.gitignore`,
+/// `.ignore` and `.igrepignore` files and skipping hidden and VCS directories.
+#[derive(Debug, Default)]
+pub struct FileWalker {
+    roots: Vec<PathBuf>,
+    follow_symlinks: bool,
+}
+
+impl FileWalker {
+    pub fn with_follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Absolute file names, sorted so file ids are stable between builds.
+    pub fn files(&self) -> Result<Vec<String>> {
+        let mut files = self
+            .roots
+            .iter()
+            .map(|root| self.walk(root))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        files.sort();
+        files.dedup();
+        Ok(files)
+    }
+
+    fn walk(&self, root: &Path) -> Result<Vec<String>> {
+        let root = root
+            .canonicalize()
+            .map_err(|e| anyhow!("root {:?} fail. {:?}", root, e))?;
+        let files = WalkBuilder::new(&root)
+            .hidden(true)
+            .git_ignore(true)
+            .git_exclude(true)
+            .ignore(true)
+            .require_git(false)
+            .add_custom_ignore_filename(IGREP_IGNORE)
+            .follow_links(self.follow_symlinks)
+            .filter_entry(|entry| !FileWalker::is_vcs_dir(entry))
+            .build()
+            .filter_map(|entry| {
+                entry
+                    .map_err(|e| warn!("walk {:?} fail. {}", root, e))
+                    .ok()
+            })
+            .filter(|entry| entry.file_type().is_some_and(|file_type| file_type.is_file()))
+            .filter_map(|entry| entry.path().to_str().map(str::to_string))
+            .collect();
+        Ok(files)
+    }
+
+    fn is_vcs_dir(entry: &DirEntry) -> bool {
+        entry.file_type().is_some_and(|file_type| file_type.is_dir())
+            && entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| VCS_DIRS.contains(&name))
+    }
+}
+
+impl From<Vec<PathBuf>> for FileWalker {
+    fn from(roots: Vec<PathBuf>) -> Self {
+        FileWalker {
+            roots,
+            follow_symlinks: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TempDir;
+
+    #[test]
+    fn walk_honors_ignore_files() {
+        let temp = TempDir::from("walk");
+        let dir = temp.path();
+        let write = |name: &str, content: &str| {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        };
+        write("a.c", "");
+        write("build/out.o", "");
+        write("gen/table.c", "");
+        write("src/b.c", "");
+        write("src/b.log", "");
+        write(".hidden/c.c", "");
+        write(".git/config", "");
+        write("CVS/Entries", "");
+        write(".gitignore", "build/\n*.log\n");
+        write(".igrepignore", "gen/\n");
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.join("src"), dir.join("link")).unwrap();
+
+        let root = dir.canonicalize().unwrap();
+        let relative = |files: Vec<String>| {
+            files
+                .iter()
+                .map(|file| file.strip_prefix(root.to_str().unwrap()).unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        let walker = FileWalker::from(vec![dir.to_path_buf(), dir.join("src")]);
+        assert_eq!(relative(walker.files().unwrap()), vec!["/a.c", "/src/b.c"]);
+        #[cfg(unix)]
+        {
+            let walker = FileWalker::from(vec![dir.to_path_buf()]).with_follow_symlinks(true);
+            assert_eq!(
+                relative(walker.files().unwrap()),
+                vec!["/a.c", "/link/b.c", "/src/b.c"]
+            );
+        }
+    }
+}
@@ -13,6 +13,16 @@ pub struct Range {
     pub end: u32,
 }
 
+/// Byte range `[start, end)` inside a pack file, for HTTP Range requests.
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub struct ByteRange {
+    #[wasm_bindgen(readonly)]
+    pub start: u64,
+    #[wasm_bindgen(readonly)]
+    pub end: u64,
+}
+
 impl VecU8 {
     pub fn vec(self) -> Vec<u8> {
         self.vec
@@ -29,6 +39,15 @@ impl VecU8 {
 
 
 
+impl From<&crate::range::Range> for ByteRange {
+    fn from(value: &crate::range::Range) -> Self {
+        ByteRange {
+            start: value.start,
+            end: value.end(),
+        }
+    }
+}
+
 impl From<Vec<u8>> for VecU8 {
     fn from(value: Vec<u8>) -> Self {
         VecU8 { vec: value }
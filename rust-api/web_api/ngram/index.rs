@@ -3,6 +3,9 @@ use std::path::{Path, PathBuf};
 
 use wasm_bindgen::prelude::*;
 
+use crate::ngram::data::NgramPackRange;
+use crate::ngram::path::NgramShardPath;
+use crate::web_api::ngram::data::ByteRange;
 use crate::web_api::ngram::path::GetPath;
 
 #[wasm_bindgen]
@@ -14,6 +17,7 @@ pub struct NgramIndexVec {
 #[derive(Clone)]
 pub struct NgramIndex {
     ngram: crate::ngram::index::NgramIndex,
+    pack: Option<NgramPackRange>,
 }
 
 #[wasm_bindgen]
@@ -30,9 +34,16 @@ impl NgramIndexVec {
 
 #[wasm_bindgen]
 impl NgramIndex {
+    /// The ngram file, or the pack file holding it for packed indexes.
     pub fn path_str(&self, base_path: &str) -> String {
         let base_path = Path::new(base_path);
-        self.ngram.path(base_path).to_string_lossy().into_owned()
+        GetPath::path(self, base_path)
+            .to_string_lossy()
+            .into_owned()
+    }
+    /// Byte range inside `path_str` for packed indexes, whole file otherwise.
+    pub fn range(&self) -> Option<ByteRange> {
+        self.pack.map(|pack| ByteRange::from(pack.range()))
     }
 }
 
@@ -49,7 +60,10 @@ impl FileIndex {
 
 impl GetPath for NgramIndex {
     fn path(&self, base_path: &Path) -> PathBuf {
-        self.ngram.path(base_path)
+        match &self.pack {
+            Some(pack) => NgramShardPath::from(pack).path(base_path),
+            None => self.ngram.path(base_path),
+        }
     }
 }
 
@@ -71,9 +85,15 @@ impl Into<crate::ngram::index::FileIndex> for FileIndex {
     }
 }
 
-impl From<crate::ngram::index::NgramIndex> for NgramIndex {
-    fn from(value: crate::ngram::index::NgramIndex) -> Self {
-        NgramIndex { ngram: value }
+impl From<&FileIndex> for crate::ngram::index::FileIndex {
+    fn from(value: &FileIndex) -> Self {
+        value.file_index
+    }
+}
+
+impl From<(crate::ngram::index::NgramIndex, Option<NgramPackRange>)> for NgramIndex {
+    fn from((ngram, pack): (crate::ngram::index::NgramIndex, Option<NgramPackRange>)) -> Self {
+        NgramIndex { ngram, pack }
     }
 }
 
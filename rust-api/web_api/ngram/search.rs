@@ -12,14 +12,26 @@ pub struct SearchEngine {
     engine: crate::ngram::search::SearchEngine,
 }
 
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct SearchOptions {
+    options: crate::ngram::search::SearchOptions,
+}
+
 #[wasm_bindgen]
 pub struct SearchOneEngine {
     engine: crate::ngram::search::SearchOneEngine,
 }
 
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct DefinitionLines {
+    lines: crate::ngram::rank::DefinitionLines,
+}
+
 #[wasm_bindgen]
 pub struct SearchOneFilesLinesStructResult {
-    result: crate::ngram::search::SearchOneFilesLinesStructResult,
+    result: crate::ngram::search::SearchOneQueryResult,
 }
 
 #[wasm_bindgen]
@@ -40,6 +52,13 @@ impl SearchOneLineContentResult {
     pub fn content(&self) -> String {
         self.result.content().clone()
     }
+    pub fn is_context(&self) -> bool {
+        self.result.is_context()
+    }
+    /// Indexes of the query terms matching this line.
+    pub fn terms(&self) -> Vec<u32> {
+        self.result.terms().to_vec()
+    }
     pub fn match_range(&self) -> Vec<Range> {
         self.result
             .match_range()
@@ -50,34 +69,109 @@ impl SearchOneLineContentResult {
             })
             .collect()
     }
+    /// 1-based character columns of `match_range`, ready for editor decorations.
+    pub fn match_columns(&self) -> Vec<Range> {
+        self.result
+            .match_columns()
+            .into_iter()
+            .map(|(start, end)| Range { start, end })
+            .collect()
+    }
 }
 
 #[wasm_bindgen]
 impl SearchEngine {
     pub fn search(&self, pattern: &str) -> Result<SearchOneEngine, JsValue> {
         self.engine
-            .search(pattern)
+            .search_with(pattern, &crate::ngram::search::SearchOptions::default())
+            .map(SearchOneEngine::from)
+            .map_err(|e| JsValue::from_str(&format!("search error: {}", e)))
+    }
+    /// Search `literal` as is, without regex parsing.
+    pub fn search_fixed(&self, literal: &str) -> Result<SearchOneEngine, JsValue> {
+        let options = crate::ngram::search::SearchOptions::default().with_fixed_strings(true);
+        self.engine
+            .search_with(literal, &options)
             .map(SearchOneEngine::from)
             .map_err(|e| JsValue::from_str(&format!("search error: {}", e)))
     }
+    pub fn search_with(
+        &self,
+        pattern: &str,
+        options: &SearchOptions,
+    ) -> Result<SearchOneEngine, JsValue> {
+        self.engine
+            .search_with(pattern, &options.options)
+            .map(SearchOneEngine::from)
+            .map_err(|e| JsValue::from_str(&format!("search error: {}", e)))
+    }
+    /// `scan_limit` bounds the number of files a pattern without usable ngram may scan.
+    /// `path_table` is `paths.data`, needed by path filters and `SearchOneEngine::path`.
     #[wasm_bindgen(constructor)]
-    pub fn new(global_data: VecU8) -> Result<SearchEngine, JsValue> {
+    pub fn new(
+        global_data: VecU8,
+        scan_limit: Option<u32>,
+        path_table: Option<VecU8>,
+    ) -> Result<SearchEngine, JsValue> {
         let data = crate::ngram::data::GlobalData::from_data(&global_data.vec())
             .map_err(|e| JsValue::from_str(&format!("Failed to parse global data: {}", e)))?;
-        let engine = crate::ngram::search::SearchEngine::from(data);
+        let mut engine = crate::ngram::search::SearchEngine::from(data)
+            .with_scan_limit(scan_limit.map(|limit| limit as usize));
+        if let Some(path_table) = path_table {
+            let path_table = crate::ngram::data::PathTable::from_data(&path_table.vec())
+                .map_err(|e| JsValue::from_str(&format!("Failed to parse path table: {}", e)))?;
+            engine = engine.with_path_table(path_table);
+        }
         Ok(SearchEngine { engine })
     }
 }
 
+#[wasm_bindgen]
+impl SearchOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> SearchOptions {
+        SearchOptions::default()
+    }
+    pub fn set_ignore_case(&mut self, ignore_case: bool) {
+        self.options = self.options.clone().with_ignore_case(ignore_case);
+    }
+    pub fn set_fixed_strings(&mut self, fixed_strings: bool) {
+        self.options = self.options.clone().with_fixed_strings(fixed_strings);
+    }
+    pub fn set_query(&mut self, query: bool) {
+        self.options = self.options.clone().with_query(query);
+    }
+    pub fn set_multiline(&mut self, multiline: bool) {
+        self.options = self.options.clone().with_multiline(multiline);
+    }
+    pub fn set_context(&mut self, before: u32, after: u32) {
+        self.options = self.options.clone().with_context(before, after);
+    }
+    pub fn set_max_count(&mut self, max_count: Option<u32>) {
+        self.options = self.options.clone().with_max_count(max_count);
+    }
+}
+
 #[wasm_bindgen]
 impl SearchOneEngine {
+    /// Full file name of `file_index`, known without fetching the file blob.
+    pub fn path(&self, file_index: &FileIndex) -> Option<String> {
+        self.engine.path(&file_index.into())
+    }
+    /// Number of `AND`/`OR`/`NOT` separated patterns in the query.
+    pub fn terms_len(&self) -> u32 {
+        self.engine.terms_len() as u32
+    }
     pub fn ngrams(&self) -> NgramIndexVec {
         NgramIndexVec::from(
             self.engine
                 .ngrams()
                 .0
                 .into_iter()
-                .map(NgramIndex::from)
+                .map(|ngram| {
+                    let pack = self.engine.pack_range(&ngram);
+                    NgramIndex::from((ngram, pack))
+                })
                 .collect::<Vec<NgramIndex>>(),
         )
     }
@@ -100,6 +194,61 @@ impl SearchOneEngine {
             .map(SearchOneFilesLinesStructResult::from)
             .map_err(|e| JsValue::from_str(&format!("files_lines error: {}", e)))
     }
+    /// The query plan as text, same inputs as `files_lines`, see `igrep explain`.
+    pub fn explain(&self, ngrams_index: NgramIndexVec, datas: Vec<VecU8>) -> Result<String, JsValue> {
+        self.engine
+            .explain(
+                crate::ngram::index::NgramIndexVec::from(
+                    ngrams_index
+                        .vec()
+                        .into_iter()
+                        .map(|ng| ng.into())
+                        .collect::<Vec<_>>(),
+                ),
+                datas.into_iter().map(|d| d.vec()).collect::<Vec<_>>(),
+            )
+            .map(|plan| plan.to_string())
+            .map_err(|e| JsValue::from_str(&format!("explain error: {}", e)))
+    }
+    pub fn files(
+        &self,
+        result: &SearchOneFilesLinesStructResult,
+    ) -> Result<Vec<FileIndex>, JsValue> {
+        self.engine
+            .files(&result.result)
+            .map(|v| v.into_iter().map(FileIndex::from).collect::<Vec<_>>())
+            .map_err(|e| JsValue::from_str(&format!("files error: {}", e)))
+    }
+    pub fn files_batch(
+        &self,
+        result: &SearchOneFilesLinesStructResult,
+        offset: u32,
+        len: u32,
+    ) -> Result<Vec<FileIndex>, JsValue> {
+        self.engine
+            .files_batch(&result.result, offset as usize, len as usize)
+            .map(|v| v.into_iter().map(FileIndex::from).collect::<Vec<_>>())
+            .map_err(|e| JsValue::from_str(&format!("files error: {}", e)))
+    }
+    /// Page `page`, counted from 0, of the files ordered by relevance, files with
+    /// a match on one of `definitions` come first.
+    pub fn ranked_page(
+        &self,
+        result: &SearchOneFilesLinesStructResult,
+        definitions: &DefinitionLines,
+        page: u32,
+        page_size: u32,
+    ) -> Result<Vec<FileIndex>, JsValue> {
+        self.engine
+            .ranked_page(
+                &result.result,
+                &definitions.lines,
+                page as usize,
+                page_size as usize,
+            )
+            .map(|v| v.into_iter().map(FileIndex::from).collect::<Vec<_>>())
+            .map_err(|e| JsValue::from_str(&format!("ranked_page error: {}", e)))
+    }
     pub fn file_lines_match(
         &self,
         file_index: FileIndex,
@@ -111,14 +260,54 @@ impl SearchOneEngine {
             .map(SearchOneFileLinesContentResult::from)
             .map_err(|e| JsValue::from_str(&format!("file_lines_match error: {}", e)))
     }
+    /// Number of matched lines, without building their content.
+    pub fn file_match_count(
+        &self,
+        file_index: FileIndex,
+        file_data: VecU8,
+        result: &SearchOneFilesLinesStructResult,
+    ) -> Result<u32, JsValue> {
+        self.engine
+            .file_match_count(file_index.into(), file_data.vec(), &result.result)
+            .map_err(|e| JsValue::from_str(&format!("file_match_count error: {}", e)))
+    }
+    pub fn file_is_match(
+        &self,
+        file_index: FileIndex,
+        file_data: VecU8,
+        result: &SearchOneFilesLinesStructResult,
+    ) -> Result<bool, JsValue> {
+        self.engine
+            .file_is_match(file_index.into(), file_data.vec(), &result.result)
+            .map_err(|e| JsValue::from_str(&format!("file_is_match error: {}", e)))
+    }
+}
+
+#[wasm_bindgen]
+impl DefinitionLines {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds the definitions of one `definition.data` blob of the LSP index.
+    pub fn insert(&mut self, data: VecU8) -> Result<(), JsValue> {
+        use crate::lsp::data::FromToData;
+        let definitions = crate::lsp::data::DefinitionsData::from_data(data.vec().as_slice())
+            .map_err(|e| JsValue::from_str(&format!("definitions error: {}", e)))?;
+        self.lines.insert(&definitions);
+        Ok(())
+    }
 }
 
 #[wasm_bindgen]
 impl SearchOneFilesLinesStructResult {
+    pub fn is_all(&self) -> bool {
+        self.result.is_all()
+    }
     pub fn files(&self) -> Result<Vec<FileIndex>, JsValue> {
         self.result
             .files()
-            .and_then(|v| Ok(v.into_iter().map(FileIndex::from).collect::<Vec<_>>()))
+            .map(|v| v.into_iter().map(FileIndex::from).collect::<Vec<_>>())
             .map_err(|e| JsValue::from_str(&format!("files error: {}", e)))
     }
 }
@@ -128,7 +317,7 @@ impl SearchOneFileLinesContentResult {
     pub fn lines(&self) -> Vec<SearchOneLineContentResult> {
         self.result
             .lines()
-            .into_iter()
+            .iter()
             .map(|v| SearchOneLineContentResult::from(v.clone()))
             .collect::<Vec<_>>()
     }
@@ -137,13 +326,27 @@ impl SearchOneFileLinesContentResult {
     }
     pub fn is_empty(&self) -> bool {
         self.result.is_empty()
-    }   
+    }
+    /// Number of runs of adjacent matched and context lines.
+    pub fn groups_len(&self) -> u32 {
+        self.result.groups().len() as u32
+    }
+    pub fn group(&self, index: u32) -> Vec<SearchOneLineContentResult> {
+        self.result
+            .groups()
+            .get(index as usize)
+            .map(|group| {
+                group
+                    .iter()
+                    .map(|v| SearchOneLineContentResult::from((*v).clone()))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default()
+    }
 }
 
-impl From<crate::ngram::search::SearchOneFilesLinesStructResult>
-    for SearchOneFilesLinesStructResult
-{
-    fn from(value: crate::ngram::search::SearchOneFilesLinesStructResult) -> Self {
+impl From<crate::ngram::search::SearchOneQueryResult> for SearchOneFilesLinesStructResult {
+    fn from(value: crate::ngram::search::SearchOneQueryResult) -> Self {
         SearchOneFilesLinesStructResult { result: value }
     }
 }
@@ -1,8 +1,13 @@
 import { CancellablePromise } from 'real-cancellable-promise';
+import type { ByteRange } from 'igrep';
 
-export async function fetchFileData(path_base_url: String, controller?: AbortController): Promise<Uint8Array | undefined> {
+export async function fetchFileData(path_base_url: String, controller?: AbortController, range?: ByteRange): Promise<Uint8Array | undefined> {
     let header = new Headers();
     // header.append("Content-Type", "application/octet-stream");
+    if (range) {
+        // packed index: only fetch this ngram's bytes out of the shard file
+        header.append("Range", `bytes=${range.start}-${range.end - 1n}`);
+    }
     let name = import.meta.env.BASE_URL + path_base_url;
     let url = new URL(name, import.meta.url);
     // console.log('Fetching file data from URL:', url);
@@ -12,6 +17,10 @@ export async function fetchFileData(path_base_url: String, controller?: AbortCon
 
         let res = await nurmal_fetch;
         let buffer = await res.arrayBuffer();
+        if (range && res.status !== 206) {
+            // the server ignored Range and sent the whole shard, cut the ngram out of it
+            return new Uint8Array(buffer, Number(range.start), Number(range.end - range.start));
+        }
         return new Uint8Array(buffer);
     } catch (err) {
         if (err instanceof DOMException && err.name === 'AbortError') {